@@ -5,6 +5,7 @@ use crate::sphere::Sphere;
 use crate::sphere_flake::generate_sphere_flake;
 use crate::vec2::vec2;
 use crate::vec3::vec3;
+use crate::vec3::Vec3;
 use crate::vec3::Y_AXIS;
 use crate::vec4::vec4;
 use crate::scene::Scene;
@@ -23,8 +24,31 @@ mod vec2;
 
 const WINDOW_WIDTH : u32 = 854;
 const WINDOW_HEIGHT: u32 = 480;
+const MAX_PASSES   : u32 = 64;
+
+// Halton sequence value, used to offset each pass to a different spot in the pixel
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    let mut result = 0.0;
+    let mut scale = 1.0 / (base as f32);
+    while (index > 0) {
+        result += ((index % base) as f32) * scale;
+        index /= base;
+        scale /= base as f32;
+    }
+    result
+}
+
+fn queue_pass(queue: &std::sync::Mutex<std::collections::VecDeque<(u32, u32)>>, pass: u32, height: u32) {
+    let mut queue = queue.lock().unwrap();
+    for y in 0..height {
+        queue.push_back((pass, y));
+    }
+}
 
 fn main() {
+    // --save-on-exit writes the image when the window is closed with Escape too
+    let save_on_exit = std::env::args().any(|arg| arg == "--save-on-exit");
+
     let mut window = minifb::Window::new(
         file!(),
         WINDOW_WIDTH as usize,
@@ -67,11 +91,11 @@ fn main() {
 
     let timer = std::time::Instant::now();
 
-    // Queue of scanlines
+    // Queue of (pass, scanline). Each pass traces one ray per pixel at a
+    // different offset inside the pixel and the window shows the running
+    // average of all passes so far.
     let scanline_queue = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
-    for y in 0..image.height {
-        scanline_queue.lock().unwrap().push_back(y);
-    }
+    queue_pass(&scanline_queue, 0, image.height);
 
     // Save these so we lock less often
     let image_width = image.width;
@@ -79,6 +103,8 @@ fn main() {
 
     // Make these accessible across thread
     let shared_image = std::sync::Arc::new(std::sync::Mutex::new(image));
+    let shared_accumulation = std::sync::Arc::new(std::sync::Mutex::new(vec![Vec3::default(); (image_width * image_height) as usize]));
+    let shared_scanlines_done = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
     let shared_scene = std::sync::Arc::new(scene);
     let shared_scanline_rendered = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shared_stop_render = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
@@ -89,6 +115,8 @@ fn main() {
     for _i in 0..num_cores {
         let local_scanlines = scanline_queue.clone();
         let local_image = shared_image.clone();
+        let local_accumulation = shared_accumulation.clone();
+        let local_scanlines_done = shared_scanlines_done.clone();
         let local_scene = shared_scene.clone();
         let local_scanline_rendered = shared_scanline_rendered.clone();
         let local_stop_render = shared_stop_render.clone();
@@ -96,36 +124,55 @@ fn main() {
             loop {
                 let scanline = local_scanlines.lock().unwrap().pop_front();
                 match scanline {
-                    Some(y) => {
+                    Some((pass, y)) => {
+                        let offset_x = radical_inverse(2, pass + 1);
+                        let offset_y = radical_inverse(3, pass + 1);
+
                         let mut stop_render = false;
+                        let mut colors = Vec::with_capacity(image_width as usize);
                         for x in 0..image_width {
                             stop_render = local_stop_render.load(std::sync::atomic::Ordering::Relaxed);
                             if (stop_render) {
                                 break;
                             }
 
-                            let u = (x as f32) / (image_width as f32);
-                            let v = (y as f32) / (image_height as f32);
+                            let u = ((x as f32) + offset_x) / (image_width as f32);
+                            let v = ((y as f32) + offset_y) / (image_height as f32);
 
                             let ray = local_scene.camera.generate_ray(vec2(u, v));
-                            let color = local_scene.trace_recursive(ray, 0, 3);
-
-                            // Swap R and B due to minifb's pixel format
-                            let r: u8 = (color.z * 255.0) as u8;
-                            let g: u8 = (color.y * 255.0) as u8;
-                            let b: u8 = (color.x * 255.0) as u8;
-
-                            // Totally not efficient but is okay for this example
-                            local_image.lock().unwrap().set_pixel(x, y, r, g, b);
+                            colors.push(local_scene.trace_recursive(ray, 0, 3));
                         }
                         if (stop_render) {
                             break;
                         }
 
+                        // Add this pass into the accumulation and show the average
+                        let scale = 1.0 / ((pass + 1) as f32);
+                        let row = (y * image_width) as usize;
+                        let mut accumulation = local_accumulation.lock().unwrap();
+                        let mut image = local_image.lock().unwrap();
+                        for (x, color) in colors.iter().enumerate() {
+                            accumulation[row + x] = accumulation[row + x] + *color;
+                            let average = accumulation[row + x] * scale;
+
+                            // Swap R and B due to minifb's pixel format
+                            let r: u8 = (average.z * 255.0) as u8;
+                            let g: u8 = (average.y * 255.0) as u8;
+                            let b: u8 = (average.x * 255.0) as u8;
+
+                            image.set_pixel(x as u32, y, r, g, b);
+                        }
+
+                        local_scanlines_done.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                         local_scanline_rendered.store(true, std::sync::atomic::Ordering::Relaxed);
-                        println!("Traced scanline {}", y);
                     },
-                    None => break,
+                    // Wait for the next pass to be queued
+                    None => {
+                        if (local_stop_render.load(std::sync::atomic::Ordering::Relaxed)) {
+                            break;
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    },
                 }
             }
         });
@@ -136,12 +183,14 @@ fn main() {
 
     let mut write_file = true;
     let mut wrote_time = false;
+    let mut passes_done = 0;
+    let mut last_title_update = std::time::Instant::now();
 
     // Loop while the window is open
     while (window.is_open()) {
         if (window.is_key_down(minifb::Key::Escape)) {
             shared_stop_render.store(true, std::sync::atomic::Ordering::Relaxed);
-            write_file = false;
+            write_file = save_on_exit;
             break;
         }
 
@@ -162,14 +211,31 @@ fn main() {
             window.update();
         }
 
-        if (scanline_queue.lock().unwrap().is_empty()) {
-            if (!wrote_time) {
+        // Start the next pass once every scanline of the current one is in
+        if (shared_scanlines_done.load(std::sync::atomic::Ordering::Relaxed) == image_height) {
+            shared_scanlines_done.store(0, std::sync::atomic::Ordering::Relaxed);
+            passes_done += 1;
+            if (passes_done < MAX_PASSES) {
+                queue_pass(&scanline_queue, passes_done, image_height);
+            }
+            else if (!wrote_time) {
                 println!("Ray trace took: {} seconds", timer.elapsed().as_secs_f32());
                 wrote_time = true;
             }
         }
+
+        if (!wrote_time && (last_title_update.elapsed().as_secs_f32() > 0.25)) {
+            last_title_update = std::time::Instant::now();
+            let scanlines = (passes_done * image_height) + shared_scanlines_done.load(std::sync::atomic::Ordering::Relaxed);
+            let samples = (scanlines as f64) * (image_width as f64);
+            let samples_per_sec = samples / timer.elapsed().as_secs_f64().max(1e-3);
+            window.set_title(&format!("{} - pass {}/{} - {:.2}M samples/sec", file!(), passes_done, MAX_PASSES, samples_per_sec / 1.0e6));
+        }
     }
 
+    // Workers wait for more passes until told to stop
+    shared_stop_render.store(true, std::sync::atomic::Ordering::Relaxed);
+
     // -------------------------------------------------------------------------
 
     for thread in threads {
@@ -0,0 +1,134 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+
+use crate::ray::Ray;
+use crate::vec3;
+use crate::vec3::*;
+
+// Axis aligned bounding box. An empty box has min > max so that growing it by
+// any point or box yields that point or box.
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Aabb::empty()
+    }
+}
+
+pub fn aabb(min: Vec3, max: Vec3) -> Aabb {
+    Aabb { min, max }
+}
+
+pub fn union(a: Aabb, b: Aabb) -> Aabb {
+    Aabb {
+        min: vec3::min(a.min, b.min),
+        max: vec3::max(a.max, b.max),
+    }
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: vec3::from_scalar(f32::INFINITY),
+            max: vec3::from_scalar(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn infinite() -> Aabb {
+        Aabb {
+            min: vec3::from_scalar(f32::NEG_INFINITY),
+            max: vec3::from_scalar(f32::INFINITY),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (self.min.x > self.max.x) || (self.min.y > self.max.y) || (self.min.z > self.max.z)
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.min.x.is_finite() && self.min.y.is_finite() && self.min.z.is_finite() &&
+        self.max.x.is_finite() && self.max.y.is_finite() && self.max.z.is_finite()
+    }
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = vec3::min(self.min, point);
+        self.max = vec3::max(self.max, point);
+    }
+
+    pub fn grow_aabb(&mut self, other: Aabb) {
+        *self = union(*self, other);
+    }
+
+    pub fn center(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn corner(&self, i: usize) -> Vec3 {
+        vec3(
+            if (i & 1) == 0 { self.min.x } else { self.max.x },
+            if (i & 2) == 0 { self.min.y } else { self.max.y },
+            if (i & 4) == 0 { self.min.z } else { self.max.z },
+        )
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let e = self.extent();
+        2.0 * ((e.x * e.y) + (e.y * e.z) + (e.z * e.x))
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let e = self.extent();
+        if (e.x >= e.y) && (e.x >= e.z) {
+            0
+        }
+        else if e.y >= e.z {
+            1
+        }
+        else {
+            2
+        }
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        (point.x >= self.min.x) && (point.y >= self.min.y) && (point.z >= self.min.z) &&
+        (point.x <= self.max.x) && (point.y <= self.max.y) && (point.z <= self.max.z)
+    }
+
+    // Slab test. inv_dir is 1/ray.dir precomputed by the caller since the same
+    // ray is tested against many boxes. Returns the entry distance on a hit
+    // within [t_min, t_max].
+    pub fn intersect(&self, ray: &Ray, inv_dir: Vec3, t_min: f32, t_max: f32) -> Option<f32> {
+        let t1 = (self.min - ray.pos) * inv_dir;
+        let t2 = (self.max - ray.pos) * inv_dir;
+        let tmin = vec3::min(t1, t2);
+        let tmax = vec3::max(t1, t2);
+        let tN = tmin.x.max(tmin.y).max(tmin.z).max(t_min);
+        let tF = tmax.x.min(tmax.y).min(tmax.z).min(t_max);
+        if tN <= tF {
+            Some(tN)
+        }
+        else {
+            None
+        }
+    }
+
+    // Distance along the ray to where it leaves the box, ignoring where it
+    // enters. Negative if the box is behind the ray.
+    pub fn exit_distance(&self, pos: Vec3, dir: Vec3) -> f32 {
+        let t1 = (self.min - pos) / dir;
+        let t2 = (self.max - pos) / dir;
+        let tmax = vec3::max(t1, t2);
+        tmax.x.min(tmax.y).min(tmax.z)
+    }
+}
@@ -0,0 +1,405 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::vec3;
+use crate::vec3::*;
+
+// Binned SAH build parameters
+const SAH_BIN_COUNT     : usize = 16;
+const MAX_LEAF_SIZE     : usize = 4;
+const TRAVERSAL_COST    : f32 = 1.0;
+const INTERSECTION_COST : f32 = 1.0;
+
+// Keeps the traversal stack a fixed size array
+const MAX_DEPTH : usize = 60;
+
+#[derive(Debug, Copy, Clone)]
+struct BvhNode {
+    bounds : Aabb,
+    // Interior node: index of the left child, the right child follows it
+    // Leaf node    : index of the first entry in Bvh::indices
+    first  : usize,
+    count  : usize, // 0 for interior nodes
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds : Aabb,
+    count  : usize,
+}
+
+// Bounding volume hierarchy over a list of items identified by their index.
+// The BVH doesn't know what the items are, the caller supplies the bounds when
+// building and an intersection function when traversing. Items with infinite
+// bounds (e.g. planes) can't be placed in the tree and are tested against
+// every ray instead.
+#[derive(Default)]
+pub struct Bvh {
+    nodes      : Vec<BvhNode>,
+    indices    : Vec<usize>, // Reordered so each leaf references a contiguous range
+    unbounded  : Vec<usize>,
+    item_count : usize,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            item_count: bounds.len(),
+            ..Default::default()
+        };
+
+        for (i, b) in bounds.iter().enumerate() {
+            if (b.is_finite() && !b.is_empty()) {
+                bvh.indices.push(i);
+            }
+            else {
+                bvh.unbounded.push(i);
+            }
+        }
+
+        if bvh.indices.is_empty() {
+            return bvh;
+        }
+
+        let centroids: Vec<Vec3> = bounds.iter().map(|b| b.center()).collect();
+
+        bvh.nodes.reserve(2 * bvh.indices.len());
+        bvh.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        let count = bvh.indices.len();
+        bvh.build_recursive(bounds, &centroids, 0, 0, count, 0);
+
+        bvh
+    }
+
+    pub fn item_count(&self) -> usize {
+        self.item_count
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_bounds(&self) -> Aabb {
+        if !self.unbounded.is_empty() {
+            return Aabb::infinite();
+        }
+        match self.nodes.first() {
+            Some(root) => root.bounds,
+            None => Aabb::empty(),
+        }
+    }
+
+    fn build_recursive(&mut self, bounds: &[Aabb], centroids: &[Vec3], node_index: usize, start: usize, end: usize, depth: usize) {
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.indices[start..end] {
+            node_bounds.grow_aabb(bounds[i]);
+            centroid_bounds.grow(centroids[i]);
+        }
+
+        let count = end - start;
+        self.nodes[node_index] = BvhNode { bounds: node_bounds, first: start, count };
+        if (count <= 1) || (depth >= MAX_DEPTH) {
+            return;
+        }
+
+        // Find the cheapest split plane across all three axes
+        let mut best_axis = usize::MAX;
+        let mut best_bin = 0;
+        let mut best_cost = f32::MAX;
+        let parent_area = node_bounds.surface_area().max(f32::MIN_POSITIVE);
+        let centroid_extent = centroid_bounds.extent();
+        for (axis, &axis_extent) in [centroid_extent.x, centroid_extent.y, centroid_extent.z].iter().enumerate() {
+            let axis_min = centroid_bounds.min[axis];
+            if (axis_extent <= 0.0) {
+                continue;
+            }
+
+            let mut bins = [Bin { bounds: Aabb::empty(), count: 0 }; SAH_BIN_COUNT];
+            let scale = (SAH_BIN_COUNT as f32) / axis_extent;
+            for &i in &self.indices[start..end] {
+                let b = (((centroids[i][axis] - axis_min) * scale) as usize).min(SAH_BIN_COUNT - 1);
+                bins[b].bounds.grow_aabb(bounds[i]);
+                bins[b].count += 1;
+            }
+
+            // Sweep from the right to get the area and count for each right side
+            let mut right_area = [0.0; SAH_BIN_COUNT];
+            let mut right_count = [0; SAH_BIN_COUNT];
+            let mut right_bounds = Aabb::empty();
+            let mut right_total = 0;
+            for b in (1..SAH_BIN_COUNT).rev() {
+                right_bounds.grow_aabb(bins[b].bounds);
+                right_total += bins[b].count;
+                right_area[b] = right_bounds.surface_area();
+                right_count[b] = right_total;
+            }
+
+            // Sweep from the left and evaluate the split between bin b-1 and b
+            let mut left_bounds = Aabb::empty();
+            let mut left_total = 0;
+            for b in 1..SAH_BIN_COUNT {
+                left_bounds.grow_aabb(bins[b - 1].bounds);
+                left_total += bins[b - 1].count;
+                if (left_total == 0) || (right_count[b] == 0) {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST + INTERSECTION_COST * (
+                    (left_bounds.surface_area() * (left_total as f32)) +
+                    (right_area[b] * (right_count[b] as f32))
+                ) / parent_area;
+                if (cost < best_cost) {
+                    best_cost = cost;
+                    best_axis = axis;
+                    best_bin = b;
+                }
+            }
+        }
+
+        let leaf_cost = INTERSECTION_COST * (count as f32);
+        let mid = if (best_axis == usize::MAX) {
+            // All centroids are in the same spot, SAH can't separate them
+            if (count <= MAX_LEAF_SIZE) {
+                return;
+            }
+            start + (count / 2)
+        }
+        else {
+            if (best_cost >= leaf_cost) && (count <= MAX_LEAF_SIZE) {
+                return;
+            }
+
+            let axis_min = centroid_bounds.min[best_axis];
+            let scale = (SAH_BIN_COUNT as f32) / (centroid_bounds.max[best_axis] - axis_min);
+            let in_left = |i: usize| -> bool {
+                let b = (((centroids[i][best_axis] - axis_min) * scale) as usize).min(SAH_BIN_COUNT - 1);
+                b < best_bin
+            };
+
+            // Partition indices in place
+            let mut left = start;
+            let mut right = end;
+            while (left < right) {
+                if in_left(self.indices[left]) {
+                    left += 1;
+                }
+                else {
+                    right -= 1;
+                    self.indices.swap(left, right);
+                }
+            }
+            left
+        };
+
+        let left_index = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+        self.nodes[node_index].first = left_index;
+        self.nodes[node_index].count = 0;
+
+        self.build_recursive(bounds, centroids, left_index, start, mid, depth + 1);
+        self.build_recursive(bounds, centroids, left_index + 1, mid, end, depth + 1);
+    }
+
+    // Finds the closest hit along the ray. intersect(index, closest_t) must
+    // return the hit distance if the item is hit after ray.t_min and closer
+    // than closest_t, which starts at ray.t_max. Children are visited front to
+    // back and any node beyond closest_t is skipped.
+    pub fn closest_hit<F>(&self, ray: &Ray, mut intersect: F) -> bool
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        let mut closest_t = ray.t_max;
+        let mut hit = false;
+
+        for &i in &self.unbounded {
+            if let Some(t) = intersect(i, closest_t) {
+                closest_t = t;
+                hit = true;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return hit;
+        }
+
+        let inv_dir = vec3::from_scalar(1.0) / ray.dir;
+        if self.nodes[0].bounds.intersect(ray, inv_dir, ray.t_min, closest_t).is_none() {
+            return hit;
+        }
+
+        let mut stack = [0usize; MAX_DEPTH + 2];
+        let mut stack_size = 1;
+        while (stack_size > 0) {
+            stack_size -= 1;
+            let node = &self.nodes[stack[stack_size]];
+
+            if (node.count > 0) {
+                for &i in &self.indices[node.first..(node.first + node.count)] {
+                    if let Some(t) = intersect(i, closest_t) {
+                        closest_t = t;
+                        hit = true;
+                    }
+                }
+                continue;
+            }
+
+            let left = node.first;
+            let right = node.first + 1;
+            let t_left = self.nodes[left].bounds.intersect(ray, inv_dir, ray.t_min, closest_t);
+            let t_right = self.nodes[right].bounds.intersect(ray, inv_dir, ray.t_min, closest_t);
+            match (t_left, t_right) {
+                (Some(tl), Some(tr)) => {
+                    // Push the far child first so the near child is visited first
+                    let (near, far) = if (tl <= tr) { (left, right) } else { (right, left) };
+                    stack[stack_size] = far;
+                    stack[stack_size + 1] = near;
+                    stack_size += 2;
+                },
+                (Some(_), None) => {
+                    stack[stack_size] = left;
+                    stack_size += 1;
+                },
+                (None, Some(_)) => {
+                    stack[stack_size] = right;
+                    stack_size += 1;
+                },
+                (None, None) => {},
+            }
+        }
+
+        hit
+    }
+
+    // Returns as soon as any item reports a hit. Used for shadow rays where
+    // the closest occluder doesn't matter. Nodes outside the ray's interval
+    // are skipped, items still have to check it themselves.
+    pub fn any_hit<F>(&self, ray: &Ray, mut intersect: F) -> bool
+    where
+        F: FnMut(usize) -> bool,
+    {
+        for &i in &self.unbounded {
+            if intersect(i) {
+                return true;
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_dir = vec3::from_scalar(1.0) / ray.dir;
+        let mut stack = [0usize; MAX_DEPTH + 2];
+        stack[0] = 0;
+        let mut stack_size = 1;
+        while (stack_size > 0) {
+            stack_size -= 1;
+            let node = &self.nodes[stack[stack_size]];
+            if node.bounds.intersect(ray, inv_dir, ray.t_min, ray.t_max).is_none() {
+                continue;
+            }
+
+            if (node.count > 0) {
+                for &i in &self.indices[node.first..(node.first + node.count)] {
+                    if intersect(i) {
+                        return true;
+                    }
+                }
+                continue;
+            }
+
+            stack[stack_size] = node.first;
+            stack[stack_size + 1] = node.first + 1;
+            stack_size += 2;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{Plane, Primitive, Sphere};
+    use crate::ray;
+    use crate::transform;
+
+    // Xorshift, enough randomness for test scenes without a dependency
+    struct TestRng(u32);
+
+    impl TestRng {
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            min + (max - min) * ((self.0 >> 8) as f32 / (1 << 24) as f32)
+        }
+
+        fn vec3(&mut self, min: f32, max: f32) -> Vec3 {
+            vec3(self.range(min, max), self.range(min, max), self.range(min, max))
+        }
+    }
+
+    // Random spheres of mixed sizes, some overlapping, plus a plane so the
+    // unbounded list gets used too
+    fn random_primitives(rng: &mut TestRng, count: usize) -> Vec<Box<dyn Primitive>> {
+        let mut prims: Vec<Box<dyn Primitive>> = Vec::new();
+        for _i in 0..count {
+            let position = rng.vec3(-10.0, 10.0);
+            let scale = vec3::from_scalar(rng.range(0.05, 1.5));
+            prims.push(Box::new(Sphere { transform: transform::transform(position, vec3::ZERO, scale), material: 0 }));
+        }
+        prims.push(Box::new(Plane { transform: transform::from_position(vec3(0.0, -12.0, 0.0)), material: 0 }));
+        prims
+    }
+
+    fn random_ray(rng: &mut TestRng) -> Ray {
+        let mut ray = ray::ray(rng.vec3(-15.0, 15.0), normalize(rng.vec3(-1.0, 1.0)));
+        ray.t_max = rng.range(1.0, 40.0);
+        ray
+    }
+
+    // Closest hit distance of prim, if it's closer than closest_t
+    fn hit_distance(prim: &dyn Primitive, ray: &Ray, closest_t: f32) -> Option<f32> {
+        prim.intersect_illum(&Ray { t_max: closest_t, ..*ray }).map(|hit| hit.t)
+    }
+
+    #[test]
+    fn traversal_matches_linear_search() {
+        let mut rng = TestRng(7);
+        let prims = random_primitives(&mut rng, 300);
+        let bounds: Vec<Aabb> = prims.iter().map(|prim| prim.get_bounds()).collect();
+        let bvh = Bvh::build(&bounds);
+        assert!(bvh.node_count() > 1);
+
+        let mut hit_count = 0;
+        let mut miss_count = 0;
+        for _i in 0..2000 {
+            let ray = random_ray(&mut rng);
+
+            let mut linear_closest: Option<(usize, f32)> = None;
+            for (i, prim) in prims.iter().enumerate() {
+                if let Some(t) = hit_distance(prim.as_ref(), &ray, linear_closest.map_or(ray.t_max, |(_, t)| t)) {
+                    linear_closest = Some((i, t));
+                }
+            }
+
+            let mut bvh_closest: Option<(usize, f32)> = None;
+            let bvh_hit = bvh.closest_hit(&ray, |i, closest_t| {
+                let t = hit_distance(prims[i].as_ref(), &ray, closest_t)?;
+                bvh_closest = Some((i, t));
+                Some(t)
+            });
+            assert_eq!(bvh_hit, linear_closest.is_some());
+            assert_eq!(bvh_closest, linear_closest);
+            if (bvh_hit) { hit_count += 1 } else { miss_count += 1 }
+
+            let linear_any = prims.iter().any(|prim| prim.intersect_shadow(&ray));
+            assert_eq!(bvh.any_hit(&ray, |i| prims[i].intersect_shadow(&ray)), linear_any);
+        }
+        // Enough of both to mean something
+        assert!((hit_count > 200) && (miss_count > 200), "{} hits, {} misses", hit_count, miss_count);
+    }
+}
@@ -1,20 +1,73 @@
 #![allow(dead_code)]
 
-use crate::{mat4, vec4};
+use std::borrow::Cow;
+
+use crate::{mat4, quat, ray, sampling, vec4};
 use crate::vec2::*;
 use crate::vec3;
 use crate::vec3::*;
 use crate::mat4::*;
+use crate::quat::Quat;
 use crate::ray::Ray;
+use std::f32::consts::PI;
+
+// How image positions map to rays. Panoramas are in the same layout as
+// environment skies, so a camera looking along +Z with +Y up renders an
+// equirectangular image that can be used as one.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    Perspective,
+    Orthographic,    // Parallel rays from a view_height tall rectangle around the eye
+    Fisheye,         // Equidistant, the angle from the view direction grows linearly out to the image circle
+    Equirectangular, // 360 by 180 degree panorama, longitude across and latitude down
+}
+
+// How the two eyes of a stereo camera share the image. The left eye is on
+// the left or on top.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StereoLayout {
+    Mono,
+    SideBySide,
+    TopBottom,
+}
+
+// Parallel eyes look straight ahead, so everything is in front of the screen
+// and the zero parallax plane is at infinity. Off-axis eyes shear their
+// frustums to agree on the zero_parallax plane without toeing in, which
+// would add vertical parallax.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Convergence {
+    Parallel,
+    OffAxis,
+}
 
+#[derive(Copy, Clone)]
 pub struct Camera {
     eye:             Vec3,
     center:          Vec3,
     up:              Vec3,
+    projection:      Projection,
     fovy:            f32, // Degrees
+    view_height:     f32, // Orthographic
+    fisheye_fov:     f32, // Degrees across the image circle, which fits the image height
     aspect_ratio:    f32,
     near_clip:       f32,
     far_clip:        f32,
+    aperture_radius: f32, // 0 is a pinhole camera with everything in focus
+    focus_distance:  f32, // Along the view direction, 0 focuses on center
+    aperture_blades: u32, // Sides of the polygonal aperture, 0 for a round one
+    blade_rotation:  f32, // Degrees
+    stereo_layout:   StereoLayout,
+    eye_separation:  f32, // Interpupillary distance in scene units
+    convergence:     Convergence,
+    zero_parallax:   f32, // Off-axis only, 0 uses the focus distance
+    moving:          bool,
+    end_eye:         Vec3,
+    end_center:      Vec3,
+    end_up:          Vec3,
+    end_orientation: Quat,
+    shutter_open:    f32,
+    shutter_close:   f32,
     view_matrix:     Mat4,
     proj_matrix:     Mat4,
     inv_view_matrix: Mat4,
@@ -27,10 +80,28 @@ impl Default for Camera {
             eye:             vec3(0.0, 0.0, -1.0),
             center:          vec3(0.0, 0.0, 0.0),
             up:              vec3(0.0, 1.0, 0.0),
+            projection:      Projection::Perspective,
             fovy:            60.0,
+            view_height:     10.0,
+            fisheye_fov:     180.0,
             aspect_ratio:    1.0,
             near_clip:       1.0,
             far_clip:        10000.0,
+            aperture_radius: 0.0,
+            focus_distance:  0.0,
+            aperture_blades: 0,
+            blade_rotation:  0.0,
+            stereo_layout:   StereoLayout::Mono,
+            eye_separation:  0.064,
+            convergence:     Convergence::OffAxis,
+            zero_parallax:   0.0,
+            moving:          false,
+            end_eye:         vec3(0.0, 0.0, -1.0),
+            end_center:      vec3(0.0, 0.0, 0.0),
+            end_up:          vec3(0.0, 1.0, 0.0),
+            end_orientation: quat::identity(),
+            shutter_open:    0.0,
+            shutter_close:   1.0,
             view_matrix:     mat4::identity(),
             proj_matrix:     mat4::identity(),
             inv_view_matrix: mat4::identity(),
@@ -48,6 +119,181 @@ impl Camera {
         self.eye
     }
 
+    pub fn get_center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn get_up(&self) -> Vec3 {
+        self.up
+    }
+
+    pub fn get_projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn get_fovy(&self) -> f32 {
+        self.fovy
+    }
+
+    pub fn get_view_height(&self) -> f32 {
+        self.view_height
+    }
+
+    pub fn get_fisheye_fov(&self) -> f32 {
+        self.fisheye_fov
+    }
+
+    pub fn get_aspect_ratio(&self) -> f32 {
+        self.aspect_ratio
+    }
+
+    pub fn get_near_clip(&self) -> f32 {
+        self.near_clip
+    }
+
+    pub fn get_far_clip(&self) -> f32 {
+        self.far_clip
+    }
+
+    pub fn get_aperture_radius(&self) -> f32 {
+        self.aperture_radius
+    }
+
+    pub fn get_aperture_blades(&self) -> u32 {
+        self.aperture_blades
+    }
+
+    pub fn get_blade_rotation(&self) -> f32 {
+        self.blade_rotation
+    }
+
+    // Distance along the view direction to the plane in focus
+    pub fn get_focus_distance(&self) -> f32 {
+        if (self.focus_distance > 0.0) {
+            self.focus_distance
+        }
+        else {
+            vec3::dot(self.center - self.eye, self.get_forward())
+        }
+    }
+
+    // Distance set with set_focus_distance() or focus_on(), 0 if the camera
+    // focuses on center
+    pub fn get_focus_setting(&self) -> f32 {
+        self.focus_distance
+    }
+
+    pub fn get_stereo_layout(&self) -> StereoLayout {
+        self.stereo_layout
+    }
+
+    pub fn get_eye_separation(&self) -> f32 {
+        self.eye_separation
+    }
+
+    pub fn get_convergence(&self) -> Convergence {
+        self.convergence
+    }
+
+    // Distance set with set_convergence(), 0 if it follows the focus distance
+    pub fn get_zero_parallax_setting(&self) -> f32 {
+        self.zero_parallax
+    }
+
+    pub fn get_zero_parallax(&self) -> f32 {
+        if (self.zero_parallax > 0.0) { self.zero_parallax } else { self.get_focus_distance() }
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    pub fn get_end_eye(&self) -> Vec3 {
+        if (self.moving) { self.end_eye } else { self.eye }
+    }
+
+    pub fn get_end_center(&self) -> Vec3 {
+        if (self.moving) { self.end_center } else { self.center }
+    }
+
+    pub fn get_end_up(&self) -> Vec3 {
+        if (self.moving) { self.end_up } else { self.up }
+    }
+
+    pub fn get_shutter_open(&self) -> f32 {
+        self.shutter_open
+    }
+
+    pub fn get_shutter_close(&self) -> f32 {
+        self.shutter_close
+    }
+
+    // Time of a ray somewhere in the shutter interval, u in [0, 1)
+    pub fn get_shutter_time(&self, u: f32) -> f32 {
+        self.shutter_open + (u * (self.shutter_close - self.shutter_open))
+    }
+
+    // Aspect ratio of each eye's part of the image
+    pub fn get_eye_aspect_ratio(&self) -> f32 {
+        match self.stereo_layout {
+            StereoLayout::Mono => self.aspect_ratio,
+            StereoLayout::SideBySide => 0.5 * self.aspect_ratio,
+            StereoLayout::TopBottom => 2.0 * self.aspect_ratio,
+        }
+    }
+
+    pub fn get_forward(&self) -> Vec3 {
+        (self.inv_view_matrix * vec4(0.0, 0.0, 1.0, 0.0)).as_vec3()
+    }
+
+    pub fn get_right(&self) -> Vec3 {
+        (self.inv_view_matrix * vec4(1.0, 0.0, 0.0, 0.0)).as_vec3()
+    }
+
+    pub fn set_aperture_radius(&mut self, radius: f32) {
+        self.aperture_radius = radius.max(0.0);
+    }
+
+    // Aperture from an f-number, taking scene units as meters and the
+    // vertical field of view as that of a 35mm camera with a 24mm tall
+    // sensor. Call after perspective().
+    pub fn set_f_stop(&mut self, f_stop: f32) {
+        let focal_length = 0.012 / (0.5 * self.fovy.to_radians()).tan();
+        self.aperture_radius = 0.5 * focal_length / f_stop;
+    }
+
+    // Polygonal aperture with blades sides rotated by rotation degrees, which
+    // gives out of focus highlights the same shape. Fewer than 3 blades is
+    // round.
+    pub fn set_aperture_shape(&mut self, blades: u32, rotation: f32) {
+        self.aperture_blades = if (blades >= 3) { blades } else { 0 };
+        self.blade_rotation = rotation;
+    }
+
+    // 0 goes back to focusing on center
+    pub fn set_focus_distance(&mut self, distance: f32) {
+        self.focus_distance = distance.max(0.0);
+    }
+
+    // Focuses on a point in world space, e.g. the hit of a ray from
+    // generate_ray()
+    pub fn focus_on(&mut self, point: Vec3) {
+        self.focus_distance = vec3::dot(point - self.eye, self.get_forward()).max(1e-3);
+    }
+
+    // Renders both eyes into one image. An equirectangular camera becomes an
+    // omni-directional stereo panorama for VR, usually top-bottom.
+    pub fn set_stereo(&mut self, layout: StereoLayout, eye_separation: f32) {
+        self.stereo_layout = layout;
+        self.eye_separation = eye_separation.max(0.0);
+    }
+
+    // zero_parallax of 0 follows the focus distance
+    pub fn set_convergence(&mut self, convergence: Convergence, zero_parallax: f32) {
+        self.convergence = convergence;
+        self.zero_parallax = zero_parallax.max(0.0);
+    }
+
     pub fn look_at(&mut self, eye: Vec3, center: Vec3, up: Vec3) {
         self.eye = eye;
         self.center = center;
@@ -56,7 +302,50 @@ impl Camera {
         self.inv_view_matrix = mat4::inverse(self.view_matrix);
     }
 
+    // Pose at time 1 for a camera that moves from its look_at() pose at time
+    // 0. The eye moves in a straight line and the view direction turns along
+    // the shortest arc, so a little end pose jitter gives camera shake.
+    pub fn set_end_pose(&mut self, eye: Vec3, center: Vec3, up: Vec3) {
+        self.moving = true;
+        self.end_eye = eye;
+        self.end_center = center;
+        self.end_up = up;
+        self.end_orientation = quat::from_mat4(mat4::inverse(mat4::look_at_LH(eye, center, up)));
+    }
+
+    pub fn clear_end_pose(&mut self) {
+        self.moving = false;
+    }
+
+    // Interval of time rays are spread over, where the poses of moving
+    // cameras and primitives are at 0 and 1. An open and close the same
+    // freezes everything at that time.
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter_open = open;
+        self.shutter_close = close.max(open);
+    }
+
+    // Camera posed where it is at time, clamped to the motion. Cameras that
+    // don't move are borrowed as they are.
+    pub fn at_time(&self, time: f32) -> Cow<'_, Camera> {
+        if (!self.moving) {
+            return Cow::Borrowed(self);
+        }
+        let t = time.clamp(0.0, 1.0);
+        let eye = vec3::mix(self.eye, self.end_eye, t);
+        let start_distance = vec3::length(self.center - self.eye);
+        let end_distance = vec3::length(self.end_center - self.end_eye);
+        let distance = start_distance + (t * (end_distance - start_distance));
+        let orientation = quat::slerp(quat::from_mat4(self.inv_view_matrix), self.end_orientation, t);
+        let rotation = quat::to_mat4(orientation);
+
+        let mut camera = *self;
+        camera.look_at(eye, eye + (distance * rotation[2].as_vec3()), rotation[1].as_vec3());
+        Cow::Owned(camera)
+    }
+
     pub fn perspective(&mut self, fovy: f32, aspect_ratio: f32, near_clip: f32, far_clip: f32) {
+        self.projection = Projection::Perspective;
         self.fovy = fovy;
         self.aspect_ratio = aspect_ratio;
         self.near_clip = near_clip;
@@ -65,18 +354,126 @@ impl Camera {
         self.inv_proj_matrix = mat4::inverse(self.proj_matrix);
     }
 
-    // Expected range of uv is [0, 1)
-    pub fn generate_ray(&self, uv: Vec2) -> Ray {
+    // The other projections keep the perspective fovy and clip planes for
+    // when the camera goes back to perspective
+    pub fn orthographic(&mut self, view_height: f32, aspect_ratio: f32) {
+        self.projection = Projection::Orthographic;
+        self.view_height = view_height;
+        self.aspect_ratio = aspect_ratio;
+    }
+
+    // fov can go up to 360 degrees, which sees all the way round to behind
+    // the camera at the edge of the image circle
+    pub fn fisheye(&mut self, fov: f32, aspect_ratio: f32) {
+        self.projection = Projection::Fisheye;
+        self.fisheye_fov = fov.clamp(1.0, 360.0);
+        self.aspect_ratio = aspect_ratio;
+    }
+
+    // Render at a 2:1 aspect ratio for square pixels
+    pub fn equirectangular(&mut self) {
+        self.projection = Projection::Equirectangular;
+    }
+
+    // Ray through the center of the lens, or of one eye's lens for stereo.
+    // Expected range of uv is [0, 1). Returns None outside a fisheye's image
+    // circle.
+    pub fn generate_ray(&self, uv: Vec2) -> Option<Ray> {
+        // Side is -1 for the left eye and 1 for the right, uv is moved to the
+        // eye's part of the image
+        let (side, uv) = match self.stereo_layout {
+            StereoLayout::Mono => (0.0, uv),
+            StereoLayout::SideBySide if (uv.x < 0.5) => (-1.0, vec2(2.0 * uv.x, uv.y)),
+            StereoLayout::SideBySide => (1.0, vec2((2.0 * uv.x) - 1.0, uv.y)),
+            StereoLayout::TopBottom if (uv.y < 0.5) => (-1.0, vec2(uv.x, 2.0 * uv.y)),
+            StereoLayout::TopBottom => (1.0, vec2(uv.x, (2.0 * uv.y) - 1.0)),
+        };
+        let ray = self.generate_center_ray(uv, self.get_eye_aspect_ratio())?;
+        if (side == 0.0) {
+            return Some(ray);
+        }
+
+        let half_separation = 0.5 * side * self.eye_separation;
+        if (self.projection == Projection::Equirectangular) {
+            // Omni-directional stereo, the eyes sit on a circle and look
+            // along its tangents. The offset fades out towards the poles
+            // where left and right stop meaning anything.
+            // https://developers.google.com/vr/jump/rendering-ods-content.pdf
+            let up = (self.inv_view_matrix * vec4(0.0, 1.0, 0.0, 0.0)).as_vec3();
+            return Some(ray::ray(ray.pos + (half_separation * cross(up, ray.dir)), ray.dir));
+        }
+
+        // Parallel orthographic eyes see the same image, they need off-axis
+        // convergence for any depth
+        let origin = ray.pos + (half_separation * self.get_right());
+        let cos_theta = vec3::dot(ray.dir, self.get_forward());
+        if (self.convergence == Convergence::Parallel) || (cos_theta <= 0.0) {
+            return Some(ray::ray(origin, ray.dir));
+        }
+        let target = ray.pos + ray.dir * (self.get_zero_parallax() / cos_theta);
+        Some(ray::ray(origin, vec3::normalize(target - origin)))
+    }
+
+    // Ray from the camera's eye for an image of the given aspect ratio
+    fn generate_center_ray(&self, uv: Vec2, aspect_ratio: f32) -> Option<Ray> {
         let mut d = (uv * 2.0) - 1.0;
         d.y = -d.y;
 
-        let origin    = (self.inv_view_matrix * vec4(0.0, 0.0, 0.0, 1.0)).as_vec3();
-        let target    = (self.inv_proj_matrix * vec4(d.x, d.y, 1.0, 1.0)).as_vec3();
-        let direction = (self.inv_view_matrix * vec4::as_vec4(target, 0.0)).as_vec3();
+        let origin = (self.inv_view_matrix * vec4(0.0, 0.0, 0.0, 1.0)).as_vec3();
+        let local_direction = match self.projection {
+            Projection::Perspective => {
+                let x = d.x * aspect_ratio / self.aspect_ratio;
+                (self.inv_proj_matrix * vec4(x, d.y, 1.0, 1.0)).as_vec3()
+            },
+            Projection::Orthographic => {
+                let half_height = 0.5 * self.view_height;
+                let offset = (self.inv_view_matrix * vec4(d.x * half_height * aspect_ratio, d.y * half_height, 0.0, 0.0)).as_vec3();
+                return Some(ray::ray(origin + offset, vec3::normalize(self.get_forward())));
+            },
+            Projection::Fisheye => {
+                let x = d.x * aspect_ratio;
+                let r = ((x * x) + (d.y * d.y)).sqrt();
+                if (r > 1.0) {
+                    return None;
+                }
+                let theta = r * 0.5 * self.fisheye_fov.to_radians();
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                let (dx, dy) = if (r > 0.0) { (x / r, d.y / r) } else { (0.0, 0.0) };
+                vec3(sin_theta * dx, sin_theta * dy, cos_theta)
+            },
+            Projection::Equirectangular => {
+                let phi = (uv.x - 0.5) * 2.0 * PI;
+                let theta = (0.5 - uv.y) * PI;
+                vec3(theta.cos() * phi.sin(), theta.sin(), theta.cos() * phi.cos())
+            },
+        };
+        let direction = (self.inv_view_matrix * vec4::as_vec4(local_direction, 0.0)).as_vec3();
+
+        Some(ray::ray(origin, vec3::normalize(direction)))
+    }
 
-        Ray {
-            pos: origin,
-            dir: vec3::normalize(direction),
+    // Thin lens ray through the point on the aperture picked by (u1, u2).
+    // Every ray through uv meets at the same point on the focus plane, so
+    // only things at the focus distance are sharp. Fisheyes and panoramas
+    // don't have a lens and are always in focus.
+    pub fn generate_lens_ray(&self, uv: Vec2, u1: f32, u2: f32) -> Option<Ray> {
+        let ray = self.generate_ray(uv)?;
+        let has_lens = (self.projection == Projection::Perspective) || (self.projection == Projection::Orthographic);
+        if (self.aperture_radius <= 0.0) || !has_lens {
+            return Some(ray);
         }
+
+        let forward = self.get_forward();
+        let focus_point = ray.pos + ray.dir * (self.get_focus_distance() / vec3::dot(ray.dir, forward));
+        let (x, y) = if (self.aperture_blades >= 3) {
+            sampling::uniform_sample_polygon(u1, u2, self.aperture_blades, self.blade_rotation.to_radians())
+        }
+        else {
+            sampling::uniform_sample_disk(u1, u2)
+        };
+        let up = (self.inv_view_matrix * vec4(0.0, 1.0, 0.0, 0.0)).as_vec3();
+        let origin = ray.pos + (self.aperture_radius * ((x * self.get_right()) + (y * up)));
+
+        Some(ray::ray(origin, vec3::normalize(focus_point - origin)))
     }
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+use crate::film::{Film, FilmSample};
+use crate::sampler;
+use crate::sampler::SamplerType;
+use crate::scene::Scene;
+use crate::vec3;
+
+// Renders the scene with each sampler at 1, 2, 4 ... max_samples samples per
+// pixel and prints the RMS error against a reference rendered with many
+// more independent samples. Lower is better, and the slope shows how fast
+// each sampler converges. The max_samples image of each sampler is saved
+// next to the reference for a look at the noise.
+pub fn run(scene: &mut Scene, width: u32, height: u32, max_samples: u32) {
+    let max_samples = max_samples.max(1);
+    let reference_samples = 16 * max_samples;
+    println!("Rendering a {}x{} reference with {} samples per pixel", width, height, reference_samples);
+    scene.settings.sampler = SamplerType::Independent;
+    scene.settings.samples_per_pixel = reference_samples;
+    let reference = render(scene, width, height);
+    reference.to_bitmap().write_png("convergence_reference.png");
+
+    print!("{:<8}", "samples");
+    for sampler_type in sampler::SAMPLER_TYPES {
+        print!(" {:>12}", sampler::get_sampler_name(sampler_type));
+    }
+    println!();
+
+    let mut final_films = Vec::new();
+    let mut samples = 1;
+    while (samples <= max_samples) {
+        print!("{:<8}", samples);
+        for sampler_type in sampler::SAMPLER_TYPES {
+            scene.settings.sampler = sampler_type;
+            scene.settings.samples_per_pixel = samples;
+            let film = render(scene, width, height);
+            print!(" {:>12.6}", rms_error(&film, &reference));
+            if (samples * 2 > max_samples) {
+                final_films.push((sampler_type, film));
+            }
+        }
+        println!();
+        samples *= 2;
+    }
+
+    for (sampler_type, film) in final_films {
+        film.to_bitmap().write_png(&format!("convergence_{}.png", sampler::get_sampler_name(sampler_type)));
+    }
+}
+
+// Every sample of every pixel, rows shared out between threads
+fn render(scene: &Scene, width: u32, height: u32) -> Film {
+    let film = std::sync::Mutex::new(Film::new(width, height));
+    let next_row = std::sync::atomic::AtomicU32::new(0);
+    std::thread::scope(|scope| {
+        for _i in 0..num_cpus::get() {
+            scope.spawn(|| {
+                let mut sampler = scene.create_sampler();
+                let mut samples = Vec::new();
+                loop {
+                    let y = next_row.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if (y >= height) {
+                        break;
+                    }
+                    samples.clear();
+                    for index in 0..scene.get_samples_per_pixel() {
+                        for x in 0..width {
+                            sampler.start_pixel_sample(x, y, index);
+                            let (film_x, film_y) = scene.get_film_position(x, y, &mut sampler);
+                            let color = scene.trace_sample(film_x, film_y, width, height, &mut sampler);
+                            samples.push(FilmSample { x: film_x, y: film_y, color });
+                        }
+                    }
+                    film.lock().unwrap().splat_samples(&samples, &scene.settings.filter);
+                }
+            });
+        }
+    });
+    film.into_inner().unwrap()
+}
+
+// Over the displayed 0-1 range, so a few fireflies don't drown out the rest
+fn rms_error(film: &Film, reference: &Film) -> f32 {
+    let clamp = |v| vec3::min(vec3::max(v, vec3::ZERO), vec3::ONE);
+    let mut sum = 0.0;
+    for y in 0..film.height {
+        for x in 0..film.width {
+            let d = clamp(film.get_pixel(x, y)) - clamp(reference.get_pixel(x, y));
+            sum += (d.x * d.x + d.y * d.y + d.z * d.z) as f64 / 3.0;
+        }
+    }
+    (sum / ((film.width * film.height) as f64)).sqrt() as f32
+}
@@ -0,0 +1,120 @@
+#![allow(dead_code)]
+
+use crate::bitmap::Bitmap;
+use crate::filter::Filter;
+use crate::vec3;
+use crate::vec3::*;
+
+// Accumulates weighted color samples for each pixel. A pixel's color is the
+// weighted average of everything added to it, so passes can keep being added
+// to refine the image.
+pub struct Film {
+    pub width  : u32,
+    pub height : u32,
+    sums       : Vec<Vec3>,
+    weights    : Vec<f32>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        let count = (width * height) as usize;
+        Film {
+            width,
+            height,
+            sums    : vec![vec3::ZERO; count],
+            weights : vec![0.0; count],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.sums.fill(vec3::ZERO);
+        self.weights.fill(0.0);
+    }
+
+    pub fn add_sample(&mut self, x: u32, y: u32, color: Vec3, weight: f32) {
+        let index = (y * self.width + x) as usize;
+        self.sums[index] += weight * color;
+        self.weights[index] += weight;
+    }
+
+    // Adds a sample at film position (x, y) to every pixel the filter
+    // reaches, pixel (i, j) covers [i, i + 1) x [j, j + 1)
+    pub fn splat(&mut self, sample: &FilmSample, filter: &Filter) {
+        let x0 = (sample.x - 0.5 - filter.radius).ceil().max(0.0) as u32;
+        let y0 = (sample.y - 0.5 - filter.radius).ceil().max(0.0) as u32;
+        let x1 = ((sample.x - 0.5 + filter.radius).floor() as i32).min((self.width as i32) - 1);
+        let y1 = ((sample.y - 0.5 + filter.radius).floor() as i32).min((self.height as i32) - 1);
+        for y in y0..((y1 + 1).max(0) as u32) {
+            for x in x0..((x1 + 1).max(0) as u32) {
+                let weight = filter.evaluate((x as f32) + 0.5 - sample.x, (y as f32) + 0.5 - sample.y);
+                if (weight != 0.0) {
+                    self.add_sample(x, y, sample.color, weight);
+                }
+            }
+        }
+    }
+
+    pub fn splat_samples(&mut self, samples: &[FilmSample], filter: &Filter) {
+        for sample in samples.iter() {
+            self.splat(sample, filter);
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Vec3 {
+        let index = (y * self.width + x) as usize;
+        let weight = self.weights[index];
+        if (weight > 0.0) { self.sums[index] / weight } else { vec3::ZERO }
+    }
+
+    // Pixels as 0x00RRGGBB, the format minifb expects
+    pub fn write_rgb32(&self, pixels: &mut [u32]) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r, g, b) = to_rgb8(self.get_pixel(x, y));
+                pixels[(y * self.width + x) as usize] = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+            }
+        }
+    }
+
+    // Linear colors as a little endian PFM, e.g. for panoramas to use as
+    // environment skies
+    pub fn write_pfm(&self, file_path: &str) {
+        let mut data = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let color = self.get_pixel(x, y);
+                for value in [color.x, color.y, color.z] {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        match std::fs::write(file_path, data) {
+            Ok(()) => println!("Wrote PFM file: {}", file_path),
+            Err(err) => println!("Failed to write {}: {}", file_path, err),
+        }
+    }
+
+    pub fn to_bitmap(&self) -> Bitmap {
+        let mut image = Bitmap::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r, g, b) = to_rgb8(self.get_pixel(x, y));
+                image.set_pixel(x, y, r, g, b);
+            }
+        }
+        image
+    }
+}
+
+// A traced color and where on the film it was traced, in pixels
+#[derive(Debug, Copy, Clone)]
+pub struct FilmSample {
+    pub x     : f32,
+    pub y     : f32,
+    pub color : Vec3,
+}
+
+// Out of range values are clamped by the float to int cast
+fn to_rgb8(color: Vec3) -> (u8, u8, u8) {
+    ((color.x * 255.0) as u8, (color.y * 255.0) as u8, (color.z * 255.0) as u8)
+}
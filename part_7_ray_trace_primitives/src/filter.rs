@@ -0,0 +1,96 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+// Pixel reconstruction filters. Each sample is splatted into every pixel whose
+// center is within radius of it, weighted by the filter at the offset, and a
+// pixel is the weighted average of what lands on it. All filters are
+// separable, the 2D weight is the product of the x and y weights.
+// https://pbr-book.org/3ed-2018/Sampling_and_Reconstruction/Image_Reconstruction
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterType {
+    Box,      // Plain average of the samples inside the pixel at radius 0.5
+    Tent,     // Linear falloff to zero at radius
+    Gaussian, // Smooth and slightly blurry
+    Mitchell, // Mitchell-Netravali with B = C = 1/3, sharper with a small negative lobe
+    Lanczos,  // Windowed sinc, sharpest but can ring around edges
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Filter {
+    pub filter_type : FilterType,
+    pub radius      : f32, // In pixels
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            filter_type : FilterType::Box,
+            radius      : 0.5,
+        }
+    }
+}
+
+// Radius each filter is usually used with
+pub fn get_default_radius(filter_type: FilterType) -> f32 {
+    match filter_type {
+        FilterType::Box => 0.5,
+        FilterType::Tent => 1.0,
+        FilterType::Gaussian => 1.5,
+        FilterType::Mitchell => 2.0,
+        FilterType::Lanczos => 3.0,
+    }
+}
+
+impl Filter {
+    // Weight of a sample dx, dy pixels away from a pixel center
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if (x > self.radius) {
+            return 0.0;
+        }
+        match self.filter_type {
+            FilterType::Box => 1.0,
+            FilterType::Tent => 1.0 - (x / self.radius),
+            FilterType::Gaussian => {
+                // Shifted down so it reaches zero at the radius instead of
+                // being cut off
+                let sigma = self.radius / 3.0;
+                let gaussian = |x: f32| (-(x * x) / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(self.radius)).max(0.0)
+            },
+            FilterType::Mitchell => mitchell_1d(2.0 * x / self.radius, 1.0 / 3.0, 1.0 / 3.0),
+            FilterType::Lanczos => sinc(x) * sinc(x / self.radius),
+        }
+    }
+}
+
+// Mitchell-Netravali cubic over [0, 2]
+// https://www.cs.utexas.edu/~fussell/courses/cs384g-fall2013/lectures/mitchell/Mitchell.pdf
+fn mitchell_1d(x: f32, B: f32, C: f32) -> f32 {
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if (x < 1.0) {
+        (((12.0 - 9.0 * B - 6.0 * C) * x3) + ((-18.0 + 12.0 * B + 6.0 * C) * x2) + (6.0 - 2.0 * B)) / 6.0
+    }
+    else if (x < 2.0) {
+        (((-B - 6.0 * C) * x3) + ((6.0 * B + 30.0 * C) * x2) + ((-12.0 * B - 48.0 * C) * x) + (8.0 * B + 24.0 * C)) / 6.0
+    }
+    else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if (x < 1e-5) {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
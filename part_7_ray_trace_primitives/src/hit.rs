@@ -0,0 +1,92 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+
+use crate::material::MaterialId;
+use crate::ray::Ray;
+use crate::sampling;
+use crate::vec2::{vec2, Vec2};
+use crate::vec3::*;
+
+// Everything shading needs to know about where a ray hit a primitive.
+// Normals face the side the ray came from and front_face says whether that's
+// the outside of the surface. T and B complete an orthonormal frame around
+// the shading normal, with T along the direction u increases in when the
+// primitive knows it.
+#[derive(Debug, Copy, Clone)]
+pub struct HitRecord {
+    pub t           : f32,
+    pub P           : Vec3,
+    pub geometric_N : Vec3, // Normal of the actual surface
+    pub N           : Vec3, // Shading normal, e.g. interpolated across a mesh triangle
+    pub T           : Vec3,
+    pub B           : Vec3,
+    pub front_face  : bool,
+    pub uv          : Vec2,
+    pub time        : f32,   // Of the ray, for the rays leaving the hit
+    pub prim_index  : usize, // Index into Scene::primitives, filled in by the scene
+    pub material    : MaterialId,
+}
+
+// Hit at t along the ray, where the surface has the outward facing unit
+// normal N. The shading normal starts out the same, with an arbitrary
+// tangent frame and zero UVs.
+pub fn hit_record(ray: &Ray, t: f32, N: Vec3, material: MaterialId) -> HitRecord {
+    let front_face = dot(ray.dir, N) < 0.0;
+    let N = if (front_face) { N } else { -N };
+    let (T, B) = sampling::make_basis(N);
+    HitRecord {
+        t,
+        P           : ray.at(t),
+        geometric_N : N,
+        N,
+        T,
+        B,
+        front_face,
+        uv          : vec2(0.0, 0.0),
+        time        : ray.time,
+        prim_index  : usize::MAX,
+        material,
+    }
+}
+
+impl HitRecord {
+    // N is outward facing like the geometric normal passed to hit_record()
+    // and gets flipped the same way
+    pub fn set_shading_normal(&mut self, N: Vec3) {
+        self.N = if (self.front_face) { N } else { -N };
+        let (T, B) = sampling::make_basis(self.N);
+        self.T = T;
+        self.B = B;
+    }
+
+    // Points T along dpdu, made perpendicular to the shading normal. The
+    // frame is left alone if dpdu is parallel to the normal.
+    pub fn set_tangent(&mut self, dpdu: Vec3) {
+        let T = dpdu - (dot(dpdu, self.N) * self.N);
+        if (length2(T) > 1e-12) {
+            self.T = normalize(T);
+            self.B = cross(self.N, self.T);
+        }
+    }
+
+    // Mirror direction of dir about the shading normal. Falls back to the
+    // geometric normal when a perturbed normal would send it into the surface.
+    pub fn reflect(&self, dir: Vec3) -> Vec3 {
+        let R = normalize(reflect(dir, self.N));
+        if (dot(R, self.geometric_N) > 0.0) { R } else { normalize(reflect(dir, self.geometric_N)) }
+    }
+
+    // Direction dir refracts in through the shading normal, or None on total
+    // internal reflection. Falls back to the geometric normal when a perturbed
+    // normal would keep it on the side it came from.
+    pub fn refract(&self, dir: Vec3, eta: f32) -> Option<Vec3> {
+        let R = normalize(refract(dir, self.N, eta)?);
+        if (dot(R, self.geometric_N) < 0.0) { Some(R) } else { refract(dir, self.geometric_N, eta).map(normalize) }
+    }
+
+    // Surface UV and the world space direction u increases in
+    pub fn set_uv(&mut self, uv: Vec2, dpdu: Vec3) {
+        self.uv = uv;
+        self.set_tangent(dpdu);
+    }
+}
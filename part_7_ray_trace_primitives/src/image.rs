@@ -0,0 +1,380 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+use std::fmt;
+
+use crate::png;
+use crate::vec3;
+use crate::vec3::*;
+
+// RGB image with float channels, loaded from PNG or PPM for textures, or
+// from Radiance HDR or PFM for high dynamic range environment maps. 8 and
+// 16 bit values are scaled to 0-1 and used as stored, without converting
+// from sRGB, to match how colors are written out.
+pub struct Image {
+    pub width  : u32,
+    pub height : u32,
+    pixels     : Vec<Vec3>, // Rows from the top down
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ImageError {
+    fn from(err: std::io::Error) -> Self {
+        ImageError::Io(err)
+    }
+}
+
+// Largest width or height read from a file, so that a corrupt header can't
+// ask for a huge allocation
+pub const MAX_IMAGE_SIZE: u32 = 16384;
+
+// Number of samples in an image read from a file, None if it's empty or
+// bigger than MAX_IMAGE_SIZE on a side
+pub fn sample_count(width: u32, height: u32, channels: usize) -> Option<usize> {
+    if (width == 0) || (height == 0) || (width > MAX_IMAGE_SIZE) || (height > MAX_IMAGE_SIZE) {
+        return None;
+    }
+    (width as usize).checked_mul(height as usize)?.checked_mul(channels)
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Image {
+        let count = (width as usize).checked_mul(height as usize).expect("image is too large");
+        Image { width, height, pixels: vec![vec3::ZERO; count] }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Vec3 {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Vec3) {
+        self.pixels[y as usize * self.width as usize + x as usize] = color;
+    }
+}
+
+// Picks the format from the file contents rather than the extension
+pub fn load_image(file_path: &str) -> Result<Image, ImageError> {
+    let data = std::fs::read(file_path)?;
+    if data.starts_with(b"\x89PNG") {
+        let png = png::decode_png(&data).map_err(ImageError::Format)?;
+        let pixels = png.rgb.chunks(3).map(|c| vec3(c[0] as f32, c[1] as f32, c[2] as f32) / 255.0).collect();
+        Ok(Image { width: png.width, height: png.height, pixels })
+    }
+    else if data.starts_with(b"P3") || data.starts_with(b"P6") {
+        parse_ppm(&data)
+    }
+    else if data.starts_with(b"PF") || data.starts_with(b"Pf") {
+        parse_pfm(&data)
+    }
+    else if data.starts_with(b"#?") {
+        parse_hdr(&data)
+    }
+    else {
+        Err(ImageError::Format("unknown image format, expected PNG, PPM, PFM or Radiance HDR".to_string()))
+    }
+}
+
+// Binary (P6) or text (P3) PPM. The header is the magic number, width,
+// height and maximum value separated by whitespace, with # comments.
+pub fn parse_ppm(data: &[u8]) -> Result<Image, ImageError> {
+    let error = |message: &str| ImageError::Format(message.to_string());
+    let binary = data.starts_with(b"P6");
+
+    // Next whitespace separated token from pos
+    let next_token = |pos: &mut usize| -> Option<String> {
+        loop {
+            while (*pos < data.len()) && data[*pos].is_ascii_whitespace() {
+                *pos += 1;
+            }
+            if (*pos < data.len()) && (data[*pos] == b'#') {
+                while (*pos < data.len()) && (data[*pos] != b'\n') {
+                    *pos += 1;
+                }
+                continue;
+            }
+            break;
+        }
+        let start = *pos;
+        while (*pos < data.len()) && !data[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if (start == *pos) { None } else { Some(String::from_utf8_lossy(&data[start..*pos]).to_string()) }
+    };
+
+    let mut pos = 2;
+    let mut header = [0u32; 3];
+    for value in header.iter_mut() {
+        *value = next_token(&mut pos).and_then(|token| token.parse().ok()).ok_or_else(|| error("PPM header is invalid"))?;
+    }
+    let [width, height, max_value] = header;
+    if (max_value == 0) || (max_value > 65535) {
+        return Err(error("PPM header is invalid"));
+    }
+    let count = sample_count(width, height, 3).ok_or_else(|| error("PPM size is invalid or too large"))?;
+
+    // Binary samples are two bytes, big endian, when the maximum is over 255.
+    // Text ones take at least a byte each, so either way check there's
+    // enough data before allocating for them.
+    let size = if (binary && (max_value > 255)) { 2 } else { 1 };
+    if (count * size > data.len().saturating_sub(pos + 1)) {
+        return Err(error(if (binary) { "PPM data is too short" } else { "PPM data is too short or invalid" }));
+    }
+
+    let mut samples = Vec::with_capacity(count);
+    if (binary) {
+        // A single whitespace character separates the header from the data
+        pos += 1;
+        for i in 0..count {
+            let offset = pos + i * size;
+            samples.push(if (size == 2) { u16::from_be_bytes([data[offset], data[offset + 1]]) as u32 } else { data[offset] as u32 });
+        }
+    }
+    else {
+        for _ in 0..count {
+            samples.push(next_token(&mut pos).and_then(|token| token.parse::<u32>().ok()).ok_or_else(|| error("PPM data is too short or invalid"))?);
+        }
+    }
+
+    let scale = 1.0 / max_value as f32;
+    let pixels = samples.chunks(3).map(|c| scale * vec3(c[0] as f32, c[1] as f32, c[2] as f32)).collect();
+    Ok(Image { width, height, pixels })
+}
+
+// Next line of data from pos without the newline, for text headers
+fn read_line<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    if (*pos >= data.len()) {
+        return None;
+    }
+    let start = *pos;
+    while (*pos < data.len()) && (data[*pos] != b'\n') {
+        *pos += 1;
+    }
+    let line = &data[start..*pos];
+    *pos += 1;
+    Some(line)
+}
+
+// Portable float map. The header is PF for RGB or Pf for grayscale, then the
+// width and height, then a scale whose sign gives the byte order, negative
+// for little endian. Rows of 32 bit floats follow from the bottom up.
+// http://www.pauldebevec.com/Research/HDR/PFM/
+pub fn parse_pfm(data: &[u8]) -> Result<Image, ImageError> {
+    let error = |message: &str| ImageError::Format(message.to_string());
+    let channels = if data.starts_with(b"PF") { 3 } else { 1 };
+
+    // The header is three whitespace separated tokens after the magic number
+    let mut pos = 2;
+    let mut tokens = Vec::new();
+    while (tokens.len() < 3) && (pos < data.len()) {
+        while (pos < data.len()) && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while (pos < data.len()) && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        tokens.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+    }
+    let width: u32 = tokens.first().and_then(|token| token.parse().ok()).ok_or_else(|| error("PFM header is invalid"))?;
+    let height: u32 = tokens.get(1).and_then(|token| token.parse().ok()).ok_or_else(|| error("PFM header is invalid"))?;
+    let scale: f32 = tokens.get(2).and_then(|token| token.parse().ok()).ok_or_else(|| error("PFM header is invalid"))?;
+    if (scale == 0.0) {
+        return Err(error("PFM header is invalid"));
+    }
+    let count = sample_count(width, height, channels).ok_or_else(|| error("PFM size is invalid or too large"))?;
+
+    // A single whitespace character separates the header from the data
+    pos += 1;
+    if (count * 4 > data.len().saturating_sub(pos)) {
+        return Err(error("PFM data is too short"));
+    }
+    let little_endian = scale < 0.0;
+    let sample = |i: usize| {
+        let offset = pos + i * 4;
+        let bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        if (little_endian) { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }
+    };
+
+    let mut image = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let i = ((height - 1 - y) as usize * width as usize + x as usize) * channels;
+            let color = if (channels == 3) { vec3(sample(i), sample(i + 1), sample(i + 2)) } else { vec3::from_scalar(sample(i)) };
+            image.set_pixel(x, y, color);
+        }
+    }
+    Ok(image)
+}
+
+// Radiance RGBE. A text header of variables ends at an empty line, followed
+// by the resolution and then the scanlines. Each pixel is an 8 bit mantissa
+// per channel and a shared exponent, and scanlines are usually run length
+// encoded one channel at a time. Only the usual -Y height +X width
+// orientation is supported.
+// https://www.graphics.cornell.edu/~bjw/rgbe.html
+pub fn parse_hdr(data: &[u8]) -> Result<Image, ImageError> {
+    let error = |message: &str| ImageError::Format(message.to_string());
+
+    let mut pos = 0;
+    loop {
+        let line = read_line(data, &mut pos).ok_or_else(|| error("HDR header is missing its end"))?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b"FORMAT=") && (line != b"FORMAT=32-bit_rle_rgbe") {
+            return Err(error("HDR format is not supported, expected 32-bit_rle_rgbe"));
+        }
+    }
+
+    let resolution = read_line(data, &mut pos).ok_or_else(|| error("HDR resolution is missing"))?;
+    let resolution = String::from_utf8_lossy(resolution).to_string();
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match fields.as_slice() {
+        ["-Y", height, "+X", width] => (height.parse::<u32>().ok(), width.parse::<u32>().ok()),
+        _ => return Err(error("HDR orientation is not supported, expected -Y height +X width")),
+    };
+    let (width, height) = match (width, height) {
+        (Some(width), Some(height)) if sample_count(width, height, 1).is_some() => (width, height),
+        _ => return Err(error("HDR resolution is invalid or too large")),
+    };
+
+    // Check the data could hold every scanline before allocating the image.
+    // Run length encoded channels take at least two bytes per 127 pixels.
+    let too_short = || error("HDR data is too short");
+    let min_scanline = if (8..32768).contains(&width) { 4 + 8 * width.div_ceil(127) } else { 4 * width };
+    if ((min_scanline as usize) * (height as usize) > data.len().saturating_sub(pos)) {
+        return Err(too_short());
+    }
+    let mut image = Image::new(width, height);
+    // Red, green, blue and exponent bytes of a scanline
+    let mut channels = vec![vec![0u8; width as usize]; 4];
+    for y in 0..height {
+        // New style run length encoding starts with 2 2 and the width, and
+        // is only used for widths from 8 to 32767
+        let header = data.get(pos..pos + 4).ok_or_else(too_short)?;
+        let rle = (8..32768).contains(&width) && (header[0] == 2) && (header[1] == 2) && (header[2] & 0x80 == 0);
+        if (rle) {
+            if (((header[2] as u32) << 8) | header[3] as u32) != width {
+                return Err(error("HDR scanline width doesn't match the image"));
+            }
+            pos += 4;
+            for channel in channels.iter_mut() {
+                let mut x = 0;
+                while (x < width as usize) {
+                    let count = *data.get(pos).ok_or_else(too_short)? as usize;
+                    pos += 1;
+                    // Over 128 is a run of one value, otherwise that many literal values
+                    let (count, run) = if (count > 128) { (count - 128, true) } else { (count, false) };
+                    if (count == 0) || (x + count > width as usize) {
+                        return Err(error("HDR scanline is invalid"));
+                    }
+                    for i in 0..count {
+                        let offset = if (run) { pos } else { pos + i };
+                        channel[x + i] = *data.get(offset).ok_or_else(too_short)?;
+                    }
+                    pos += if (run) { 1 } else { count };
+                    x += count;
+                }
+            }
+        }
+        else {
+            // Flat, four bytes per pixel
+            for x in 0..width as usize {
+                let bytes = data.get(pos..pos + 4).ok_or_else(too_short)?;
+                for (channel, &byte) in channels.iter_mut().zip(bytes) {
+                    channel[x] = byte;
+                }
+                pos += 4;
+            }
+        }
+
+        for x in 0..width {
+            let [r, g, b, e] = [0, 1, 2, 3].map(|c| channels[c][x as usize]);
+            let color = if (e == 0) {
+                vec3::ZERO
+            }
+            else {
+                let f = 2.0f32.powi(e as i32 - 136);
+                vec3(r as f32, g as f32, b as f32) * f
+            };
+            image.set_pixel(x, y, color);
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ppm() {
+        let image = parse_ppm(b"P6 2 1 255\n\x00\x80\xff\xff\x80\x00").unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.get_pixel(1, 0).x, 1.0);
+
+        let image = parse_ppm(b"P3\n# comment\n1 1\n100\n0 50 100\n").unwrap();
+        assert_eq!(image.get_pixel(0, 0).y, 0.5);
+    }
+
+    #[test]
+    fn rejects_truncated_ppm() {
+        for data in [&b"P6"[..], b"P6 2", b"P6 2 1 255\n\x00\x80\xff", b"P6 1 1 65535\n\x00\x00\x00", b"P3 2 1 255\n0 0 0 0"] {
+            assert!(parse_ppm(data).is_err(), "parsed {:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_ppm() {
+        for data in [&b"P6 16385 1 255\n"[..], b"P6 1 16385 255\n", b"P3 4294967295 4294967295 255\n", b"P6 16384 16384 255\n\x00"] {
+            let error = parse_ppm(data).err().unwrap().to_string();
+            assert!(error.contains("too large") || error.contains("too short"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn parses_hdr() {
+        // One flat pixel and one 8 pixel run length encoded scanline
+        let image = parse_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 1\n\x80\x40\x00\x81").unwrap();
+        assert_eq!(image.get_pixel(0, 0).x, 1.0);
+
+        let image = parse_hdr(b"#?RADIANCE\n\n-Y 1 +X 8\n\x02\x02\x00\x08\x88\x80\x88\x40\x88\x00\x88\x81").unwrap();
+        assert_eq!(image.get_pixel(7, 0).y, 0.5);
+    }
+
+    #[test]
+    fn rejects_truncated_hdr_and_pfm() {
+        for data in [&b"#?RADIANCE\n"[..], b"#?RADIANCE\n\n", b"#?RADIANCE\n\n-Y 2 +X 1\n\x80\x40\x00\x81", b"#?RADIANCE\n\n-Y 1 +X 8\n\x02\x02\x00\x08\x88\x80"] {
+            assert!(parse_hdr(data).is_err(), "parsed {:?}", String::from_utf8_lossy(data));
+        }
+        for data in [&b"PF"[..], b"PF 1 1", b"PF 1 1 -1\n\x00\x00\x80\x3f", b"Pf 2 1 -1\n\x00\x00\x80\x3f"] {
+            assert!(parse_pfm(data).is_err(), "parsed {:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_hdr_and_pfm() {
+        for data in [&b"#?RADIANCE\n\n-Y 16385 +X 1\n"[..], b"#?RADIANCE\n\n-Y 4294967295 +X 4294967295\n", b"#?RADIANCE\n\n-Y 16384 +X 16384\n\x02\x02\x40\x00"] {
+            let error = parse_hdr(data).err().unwrap().to_string();
+            assert!(error.contains("too large") || error.contains("too short"), "{}", error);
+        }
+        for data in [&b"PF 16385 1 -1\n"[..], b"PF 4294967295 4294967295 -1\n", b"PF 16384 16384 -1\n\x00\x00\x80\x3f"] {
+            let error = parse_pfm(data).err().unwrap().to_string();
+            assert!(error.contains("too large") || error.contains("too short"), "{}", error);
+        }
+    }
+}
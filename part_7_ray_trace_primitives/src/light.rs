@@ -0,0 +1,195 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+use crate::sampling;
+use crate::vec3;
+use crate::vec3::*;
+
+#[derive(Debug, Copy, Clone)]
+pub enum LightType {
+    Point,
+    Directional,
+    Spot,
+    Rect,   // One sided rectangle facing along direction
+    Disk,   // One sided disk facing along direction
+    Sphere,
+}
+
+// Point lights use position, directional lights use direction and spot lights
+// use both. Spot cone angles are the half angles in radians, full intensity
+// inside inner_angle fading to nothing at outer_angle.
+//
+// Area lights are centered on position, rect and disk lights face along
+// direction. They aren't visible to camera rays, add an emissive primitive
+// in the same place to see them. Intensity is what a point light at the
+// center would have, so an area light far away looks like a point light.
+// Each shaded point averages samples shadow rays to random points on the
+// light, which gives soft shadows.
+#[derive(Debug, Copy, Clone)]
+pub struct Light {
+    pub light_type  : LightType,
+    pub position    : Vec3,
+    pub direction   : Vec3, // Direction the light travels in, not necessarily normalized
+    pub color       : Vec3,
+    pub intensity   : f32,
+    pub inner_angle : f32,
+    pub outer_angle : f32,
+    pub width       : f32, // Rect lights
+    pub height      : f32, // Rect lights
+    pub radius      : f32, // Disk and sphere lights
+    pub samples     : u32, // Shadow rays per shaded point, area lights only
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Light {
+            light_type  : LightType::Point,
+            position    : vec3::ZERO,
+            direction   : vec3(0.0, -1.0, 0.0),
+            color       : vec3::ONE,
+            intensity   : 1.0,
+            inner_angle : 20.0_f32.to_radians(),
+            outer_angle : 30.0_f32.to_radians(),
+            width       : 1.0,
+            height      : 1.0,
+            radius      : 0.5,
+            samples     : 16,
+        }
+    }
+}
+
+// Direction and distance from a shaded point to a light, and the light's
+// irradiance at that point for a surface facing it
+pub struct LightSample {
+    pub L        : Vec3,
+    pub distance : f32, // f32::INFINITY for directional lights
+    pub radiance : Vec3,
+}
+
+pub fn point_light(position: Vec3, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Point, position, color, intensity, ..Default::default() }
+}
+
+pub fn directional_light(direction: Vec3, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Directional, direction, color, intensity, ..Default::default() }
+}
+
+pub fn spot_light(position: Vec3, direction: Vec3, inner_angle: f32, outer_angle: f32, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Spot, position, direction, inner_angle, outer_angle, color, intensity, ..Default::default() }
+}
+
+pub fn rect_light(position: Vec3, direction: Vec3, width: f32, height: f32, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Rect, position, direction, width, height, color, intensity, ..Default::default() }
+}
+
+pub fn disk_light(position: Vec3, direction: Vec3, radius: f32, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Disk, position, direction, radius, color, intensity, ..Default::default() }
+}
+
+pub fn sphere_light(position: Vec3, radius: f32, color: Vec3, intensity: f32) -> Light {
+    Light { light_type: LightType::Sphere, position, radius, color, intensity, ..Default::default() }
+}
+
+// A hard step at edge0 when both edges are the same
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if (edge1 == edge0) {
+        return if (x < edge0) { 0.0 } else { 1.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl Light {
+    pub fn is_area(&self) -> bool {
+        matches!(self.light_type, LightType::Rect | LightType::Disk | LightType::Sphere)
+    }
+
+    // Shadow rays per shaded point, always one for point like lights
+    pub fn get_sample_count(&self) -> u32 {
+        if (self.is_area()) { self.samples.max(1) } else { 1 }
+    }
+
+    // Edges of a rect light. Width runs horizontally where possible, so a
+    // light facing straight up or down has width along X and height along Z.
+    fn get_rect_axes(&self) -> (Vec3, Vec3) {
+        let N = normalize(self.direction);
+        let T = cross(vec3::Y_AXIS, N);
+        let T = if (length2(T) > 1e-6) { normalize(T) } else { vec3::X_AXIS };
+        let B = cross(N, T);
+        (T, B)
+    }
+
+    // Sample of the light as seen from P. (u1, u2) pick the point on area
+    // lights and are ignored otherwise. Returns None if the sampled point
+    // doesn't face P.
+    pub fn sample(&self, P: Vec3, u1: f32, u2: f32) -> Option<LightSample> {
+        let radiance = self.color * self.intensity;
+        match self.light_type {
+            LightType::Directional => Some(LightSample {
+                L        : -normalize(self.direction),
+                distance : f32::INFINITY,
+                radiance,
+            }),
+            LightType::Point | LightType::Spot => {
+                let to_light = self.position - P;
+                let distance = length(to_light);
+                let L = to_light / distance;
+
+                // Inverse square falloff
+                let mut attenuation = 1.0 / (distance * distance);
+                if let LightType::Spot = self.light_type {
+                    let cos_angle = dot(-L, normalize(self.direction));
+                    attenuation *= smoothstep(self.outer_angle.cos(), self.inner_angle.cos(), cos_angle);
+                }
+
+                Some(LightSample { L, distance, radiance: attenuation * radiance })
+            },
+            LightType::Rect | LightType::Disk => {
+                let light_pos = if let LightType::Rect = self.light_type {
+                    let (T, B) = self.get_rect_axes();
+                    self.position + ((u1 - 0.5) * self.width * T) + ((u2 - 0.5) * self.height * B)
+                }
+                else {
+                    let (x, y) = sampling::uniform_sample_disk(u1, u2);
+                    self.position + (self.radius * sampling::local_to_world(vec3(x, y, 0.0), normalize(self.direction)))
+                };
+
+                let to_light = light_pos - P;
+                let distance = length(to_light);
+                let L = to_light / distance;
+
+                // Lambertian emitter, only the front lights anything
+                let cos_light = dot(-L, normalize(self.direction));
+                if (cos_light <= 0.0) {
+                    return None;
+                }
+
+                Some(LightSample { L, distance, radiance: (cos_light / (distance * distance)) * radiance })
+            },
+            LightType::Sphere => {
+                // Sample the cone of directions the sphere covers
+                let to_center = self.position - P;
+                let center_distance2 = length2(to_center);
+                let radius2 = self.radius * self.radius;
+                if (center_distance2 <= radius2) {
+                    return None;
+                }
+                let center_distance = center_distance2.sqrt();
+                let cos_max = (1.0 - radius2 / center_distance2).max(0.0).sqrt();
+                let L = sampling::local_to_world(sampling::uniform_sample_cone(u1, u2, cos_max), to_center / center_distance);
+
+                // Nearest intersection with the sphere along L
+                let b = dot(L, to_center);
+                let h = (b * b - center_distance2 + radius2).max(0.0);
+                let distance = b - h.sqrt();
+
+                // Uniform radiance across the cone, scaled so the light
+                // matches a point light at the center from far away
+                let solid_angle = sampling::cone_solid_angle(cos_max);
+                Some(LightSample { L, distance, radiance: (solid_angle / (PI * radius2)) * radiance })
+            },
+        }
+    }
+}
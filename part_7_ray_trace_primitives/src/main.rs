@@ -5,77 +5,99 @@ use crate::primitives::{Cylinder, RoundedBox};
 use crate::primitives::AABox;
 use std::f32::consts::PI;
 use crate::bitmap::Bitmap;
+use crate::camera::{Camera, Projection};
+use crate::film::{Film, FilmSample};
+use crate::material::Material;
 use crate::primitives::{Ellipsoid, Goursat, Plane, Sphere, Torus};
 use crate::vec2::vec2;
-use crate::vec3::{normalize, vec3};
+use crate::vec3::{normalize, vec3, Vec3};
 use crate::vec3::Y_AXIS;
 use crate::vec4::vec4;
 use crate::scene::Scene;
 
+mod aabb;
 mod bitmap;
+mod bvh;
+mod hit;
 mod mat4;
+mod material;
+mod microfacet;
+mod mesh;
+mod image;
+mod noise;
+mod obj;
+mod png;
 mod quat;
 mod ray;
+mod sampler;
+mod sampling;
 mod primitives;
 mod sphere_flake;
 mod vec3;
 mod vec4;
 mod camera;
+mod convergence;
+mod light;
+mod film;
+mod filter;
 mod scene;
+mod scene_file;
+mod sky;
 mod vec2;
+mod texture;
 mod transform;
 
 const WINDOW_WIDTH : u32 = 1280;
 const WINDOW_HEIGHT: u32 = 720;
 
-fn main() {
-    let mut window = minifb::Window::new(
-        file!(),
-        WINDOW_WIDTH as usize,
-        WINDOW_HEIGHT as usize,
-        minifb::WindowOptions::default(),
-    ).unwrap();
-
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-
-    // -------------------------------------------------------------------------
-
-    let image = Bitmap::new(WINDOW_WIDTH, WINDOW_HEIGHT);
-    let aspect_ratio = (image.width as f32) / (image.height as f32);
-
+fn create_default_scene(aspect_ratio: f32) -> Scene {
     let mut scene = Scene::default();
     scene.camera.look_at(vec3(-4.0, 5.0, -5.0), vec3(-1.5, 1.0, 0.5), Y_AXIS);
     scene.camera.perspective(60.0, aspect_ratio, 1.0, 10000.0);
 
+    // Splits each color into the 0.7 diffuse and 0.3 specular weights these objects used to be shaded with
+    let glossy = |color: Vec3| Material { albedo: 0.7 * color, specular: 0.3 * color, reflectivity: 0.5, ..Default::default() };
+    let blue = scene.add_material(glossy(vec3(0.3, 0.7, 0.9)));
+    let yellow = scene.add_material(glossy(vec3(0.9, 0.7, 0.3)));
+    let green = scene.add_material(glossy(vec3(0.7, 0.9, 0.3)));
+    let red = scene.add_material(glossy(vec3(0.9, 0.3, 0.3)));
+    let pink = scene.add_material(glossy(vec3(0.9, 0.3, 0.83)));
+    let grey = scene.add_material(glossy(vec3(0.5, 0.5, 0.5)));
+    let black = scene.add_material(glossy(vec3(0.1, 0.1, 0.1)));
+
+    // Plane UVs are in world units, so this makes 2x2 squares
+    let checker = scene.add_texture(texture::checker_texture(vec3::ONE, vec3::from_scalar(0.5), 0.5));
+    let floor = scene.add_material(Material { albedo_texture: Some(checker), ..glossy(1.5 * vec3(0.430, 0.430, 0.440)) });
+
     scene.primitives.push(Box::new(Sphere {
         transform: transform::from_position(vec3(-2.0, 1.0, -1.0)),
-        color: vec3(0.3, 0.7, 0.9),
+        material: blue,
     }));
 
     scene.primitives.push(Box::new(Ellipsoid {
         transform: transform::from_position(vec3(1.5, 1.0, -1.0)),
         radii: vec3(1.0, 0.5, 1.5),
-        color: vec3(0.9, 0.7, 0.3),
+        material: yellow,
     }));
 
     scene.primitives.push(Box::new(Goursat {
         transform: transform::from_position(vec3(2.0, 1.0, 3.0)),
         ka: 0.3,
         kb: 0.9,
-        color: vec3(0.7, 0.9, 0.3),
+        material: green,
     }));
 
     scene.primitives.push(Box::new(Torus {
         transform: transform::transform(vec3(-2.0, 0.5, 3.0), vec3(PI/2.0, 0.0, 0.0), vec3::ONE),
         major_radius: 1.0,
         minor_radius: 0.5,
-        color: vec3(0.9, 0.3, 0.3),
+        material: red,
     }));
 
     scene.primitives.push(Box::new(AABox {
         transform: transform::transform(vec3(5.0, 1.0, 1.0), vec3(0.0, 0.0, 0.0), vec3::ONE),
         size: vec3::vec3(0.8, 1.0, 1.5),
-        color: vec3(0.9, 0.3, 0.83),
+        material: pink,
     }));
 
     scene.primitives.push(Box::new(Cylinder {
@@ -83,39 +105,180 @@ fn main() {
         start: vec3::vec3(0.0, 3.0, 0.0),
         end: vec3::vec3(0.0, 0.0, 0.0),
         radius: 1.0,
-        color: vec3(0.5, 0.5, 0.5),
+        material: grey,
     }));
 
     scene.primitives.push(Box::new(RoundedBox {
         transform: transform::transform(vec3(-5.0, 1.2, 1.0), vec3(0.0, 0.0, 0.0), vec3::ONE),
         size: vec3(0.8, 0.8, 0.8),
         radius: 0.4,
-        color: vec3(0.1, 0.1, 0.1),
+        material: black,
     }));
 
     scene.primitives.push(Box::new(Plane {
         transform: transform::from_position(vec3(0.0, 0.0, 0.0)),
-        color: 1.5 * vec3(0.430, 0.430, 0.440),
+        material: floor,
     }));
 
-    scene.light = vec3(-3.0, 10.0, -5.0);
+    // Bright enough to light the objects about 12 units away like the old light without falloff
+    scene.lights.push(light::point_light(vec3(-3.0, 10.0, -5.0), vec3::ONE, 130.0));
 
-    let timer = std::time::Instant::now();
+    scene
+}
 
-    // Queue of scanlines
-    let scanline_queue = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
-    for y in 0..image.height {
-        scanline_queue.lock().unwrap().push_back(y);
+// Camera movement from the keyboard. Arrow keys orbit around the center, W and
+// S move towards and away from it. Returns true if the camera moved.
+fn update_camera(window: &minifb::Window, camera: &mut Camera, dt: f32) -> bool {
+    let mut yaw = 0.0;
+    let mut pitch = 0.0;
+    let mut zoom = 0.0;
+    if (window.is_key_down(minifb::Key::Left))  { yaw   -= 1.0; }
+    if (window.is_key_down(minifb::Key::Right)) { yaw   += 1.0; }
+    if (window.is_key_down(minifb::Key::Up))    { pitch += 1.0; }
+    if (window.is_key_down(minifb::Key::Down))  { pitch -= 1.0; }
+    if (window.is_key_down(minifb::Key::W))     { zoom  -= 1.0; }
+    if (window.is_key_down(minifb::Key::S))     { zoom  += 1.0; }
+    if (yaw == 0.0) && (pitch == 0.0) && (zoom == 0.0) {
+        return false;
+    }
+
+    // Spherical coordinates of the eye around the center, Y up
+    let center = camera.get_center();
+    let offset = camera.get_eye() - center;
+    let distance = vec3::length(offset);
+    let mut azimuth = offset.z.atan2(offset.x);
+    let mut elevation = (offset.y / distance).clamp(-1.0, 1.0).asin();
+
+    azimuth += yaw * dt;
+    elevation = (elevation + pitch * dt).clamp(-1.5, 1.5);
+    let distance = (distance * (1.0 + zoom * dt)).max(0.1);
+
+    let eye = center + distance * vec3(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+    camera.look_at(eye, center, camera.get_up());
+    true
+}
+
+fn load_scene(scene_path: &Option<String>, aspect_ratio: f32) -> Option<Scene> {
+    let mut scene = match scene_path {
+        Some(file_path) => match scene_file::load_scene(file_path, aspect_ratio) {
+            Ok(scene) => scene,
+            Err(err) => {
+                println!("Failed to load {}: {}", file_path, err);
+                return None;
+            },
+        },
+        None => create_default_scene(aspect_ratio),
+    };
+
+    // Optional OBJ model from the command line
+    if let Some(file_path) = std::env::args().nth(1).filter(|file_path| file_path.ends_with(".obj")) {
+        let material = scene.add_material(Material::default());
+        match obj::load_triangle_mesh(&file_path, transform::from_position(vec3(0.0, 0.0, 0.0)), material) {
+            Ok(mesh) => {
+                println!("Loaded {} triangles from {}", mesh.triangle_count(), file_path);
+                scene.primitives.push(Box::new(mesh));
+            },
+            Err(err) => println!("Failed to load {}: {}", file_path, err),
+        }
+    }
+
+    scene.build_bvh();
+    Some(scene)
+}
+
+// Panoramas are also saved as HDR for use as environment skies
+fn save_film(film: &Film, camera: &Camera) {
+    film.to_bitmap().write_png("part_7_ray_trace_primitives.png");
+    if (camera.get_projection() == Projection::Equirectangular) {
+        film.write_pfm("part_7_ray_trace_primitives.pfm");
+    }
+}
+
+// Saved next to the scene file it was loaded from, so file paths in it that
+// are relative to the scene still work
+fn save_scene(scene: &Scene, scene_path: &Option<String>) {
+    let file_path = match scene_path {
+        Some(file_path) => format!("{}_saved.scene", file_path.trim_end_matches(".scene")),
+        None => String::from("part_7_ray_trace_primitives.scene"),
+    };
+    match scene_file::save_scene(scene, &file_path) {
+        Ok(()) => println!("Wrote scene file: {}", file_path),
+        Err(err) => println!("Failed to save {}: {}", file_path, err),
     }
+}
+
+fn get_modified_time(file_path: &Option<String>) -> Option<std::time::SystemTime> {
+    std::fs::metadata(file_path.as_ref()?).ok()?.modified().ok()
+}
+
+// Scanline of one progressive pass. Jobs from before the last restart have an
+// old generation and are skipped.
+struct ScanlineJob {
+    generation : u64,
+    pass       : u32,
+    y          : u32,
+}
+
+fn queue_pass(queue: &std::sync::Mutex<std::collections::VecDeque<ScanlineJob>>, generation: u64, pass: u32, height: u32) {
+    let mut queue = queue.lock().unwrap();
+    for y in 0..height {
+        queue.push_back(ScanlineJob { generation, pass, y });
+    }
+}
+
+fn main() {
+    // --convergence [max samples] compares the samplers on the scene without opening a window
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--convergence") {
+        let max_samples = args.get(index + 1).and_then(|arg| arg.parse().ok()).unwrap_or(64);
+        let (width, height) = (WINDOW_WIDTH / 4, WINDOW_HEIGHT / 4);
+        let scene_path = args.get(1).filter(|file_path| file_path.ends_with(".scene")).cloned();
+        if let Some(mut scene) = load_scene(&scene_path, (width as f32) / (height as f32)) {
+            convergence::run(&mut scene, width, height, max_samples);
+        }
+        return;
+    }
+
+    // --save-on-exit saves the image when quitting with Escape too
+    let save_on_exit = args.iter().any(|arg| arg == "--save-on-exit");
+
+    let mut window = minifb::Window::new(
+        file!(),
+        WINDOW_WIDTH as usize,
+        WINDOW_HEIGHT as usize,
+        minifb::WindowOptions::default(),
+    ).unwrap();
+
+    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+
+    // -------------------------------------------------------------------------
 
-    // Save these so we lock less often
-    let image_width = image.width;
-    let image_height = image.height;
+    let image_width = WINDOW_WIDTH;
+    let image_height = WINDOW_HEIGHT;
+    let aspect_ratio = (image_width as f32) / (image_height as f32);
+
+    let scene_path = std::env::args().nth(1).filter(|file_path| file_path.ends_with(".scene"));
+    let scene = match load_scene(&scene_path, aspect_ratio) {
+        Some(scene) => scene,
+        None => return,
+    };
+    let mut scene_modified_time = get_modified_time(&scene_path);
+    let mut pass_count = scene.get_samples_per_pixel();
+
+    println!("Arrow keys orbit the camera, W/S move it closer/further, F focuses on the mouse, P saves the image, O saves the scene, Escape quits (--save-on-exit saves the image first)");
+
+    // Each pass splats one sample per pixel into the film, passes continue until
+    // the scene's samples per pixel is reached. Moving the camera or changing
+    // the scene file starts over.
+    let scanline_queue = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
+    queue_pass(&scanline_queue, 0, 0, image_height);
 
     // Make these accessible across threads
-    let shared_image = std::sync::Arc::new(std::sync::Mutex::new(image));
-    let shared_scene = std::sync::Arc::new(scene);
-    let shared_scanline_rendered = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let shared_scene = std::sync::Arc::new(std::sync::RwLock::new(scene));
+    let shared_film = std::sync::Arc::new(std::sync::Mutex::new(Film::new(image_width, image_height)));
+    let shared_generation = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let shared_scanlines_done = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let shared_film_updated = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shared_stop_render = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
 
     // Spawn threads to ray trace each scanline
@@ -123,59 +286,61 @@ fn main() {
     let mut threads = Vec::new();
     for _i in 0..num_cores {
         let local_scanlines = scanline_queue.clone();
-        let local_image = shared_image.clone();
         let local_scene = shared_scene.clone();
-        let local_scanline_rendered = shared_scanline_rendered.clone();
+        let local_film = shared_film.clone();
+        let local_generation = shared_generation.clone();
+        let local_scanlines_done = shared_scanlines_done.clone();
+        let local_film_updated = shared_film_updated.clone();
         let local_stop_render = shared_stop_render.clone();
         let thread = std::thread::spawn(move || {
+            let mut samples = Vec::with_capacity(image_width as usize);
             loop {
-                let scanline = local_scanlines.lock().unwrap().pop_front();
-                match scanline {
-                    Some(y) => {
-                        // Get pointer to scanline
-                        let mut ptr = local_image.lock().unwrap().get_scanline(y);
-
-                        let mut stop_render = false;
-                        for x in 0..image_width {
-                            stop_render = local_stop_render.load(std::sync::atomic::Ordering::Relaxed);
-                            if (stop_render) {
-                                break;
-                            }
-
-                            let u = (x as f32) / (image_width as f32);
-                            let v = (y as f32) / (image_height as f32);
-
-                            let ray = local_scene.camera.generate_ray(vec2(u, v));
-                            let color = local_scene.trace_recursive(ray, 0, 3);
-
-                            // Swap R and B due to minifb's pixel format
-                            let r: u8 = (color.z * 255.0) as u8;
-                            let g: u8 = (color.y * 255.0) as u8;
-                            let b: u8 = (color.x * 255.0) as u8;
-
-                            // Write pixel - doesn't look like you can index using [] as you would in C/C++.
-                            unsafe {
-                                *ptr = r;
-                                ptr = ptr.offset(1);
-
-                                *ptr = g;
-                                ptr = ptr.offset(1);
-
-                                *ptr = b;
-                                ptr = ptr.offset(1);
-
-                                *ptr = 255;
-                                ptr = ptr.offset(1);
-                            }
-                        }
-                        if (stop_render) {
-                            break;
-                        }
+                if (local_stop_render.load(std::sync::atomic::Ordering::Relaxed)) {
+                    break;
+                }
 
-                        local_scanline_rendered.store(true, std::sync::atomic::Ordering::Relaxed);
-                        println!("Traced scanline {}", y);
+                let job = local_scanlines.lock().unwrap().pop_front();
+                let job = match job {
+                    Some(job) => job,
+                    None => {
+                        // Wait for the next pass or a restart
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                        continue;
                     },
-                    None => break,
+                };
+
+                let is_current = |generation: u64| generation == local_generation.load(std::sync::atomic::Ordering::Relaxed);
+                if (!is_current(job.generation)) {
+                    continue;
+                }
+
+                let filter = {
+                    let scene = local_scene.read().unwrap();
+
+                    // Pass number is the sample index within each pixel
+                    let mut sampler = scene.create_sampler();
+
+                    samples.clear();
+                    for x in 0..image_width {
+                        // Give up on the scanline as soon as it's stale
+                        if (local_stop_render.load(std::sync::atomic::Ordering::Relaxed) || !is_current(job.generation)) {
+                            break;
+                        }
+                        sampler.start_pixel_sample(x, job.y, job.pass);
+                        let (film_x, film_y) = scene.get_film_position(x, job.y, &mut sampler);
+                        let color = scene.trace_sample(film_x, film_y, image_width, image_height, &mut sampler);
+                        samples.push(FilmSample { x: film_x, y: film_y, color });
+                    }
+                    scene.settings.filter
+                };
+
+                if (samples.len() == image_width as usize) {
+                    let mut film = local_film.lock().unwrap();
+                    if (is_current(job.generation)) {
+                        film.splat_samples(&samples, &filter);
+                        local_scanlines_done.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        local_film_updated.store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
                 }
             }
         });
@@ -185,49 +350,127 @@ fn main() {
     // -------------------------------------------------------------------------
 
     let mut write_file = true;
-    let mut wrote_time = false;
+    let mut generation = 0;
+    let mut passes_done = 0;
+    let mut timer = std::time::Instant::now();
+    let mut render_seconds = 0.0;
+    let mut last_frame = std::time::Instant::now();
+    let mut last_file_check = std::time::Instant::now();
+    let mut last_title_update = std::time::Instant::now();
+    let mut pixels = vec![0u32; (image_width * image_height) as usize];
 
     // Loop while the window is open
     while (window.is_open()) {
         if (window.is_key_down(minifb::Key::Escape)) {
-            shared_stop_render.store(true, std::sync::atomic::Ordering::Relaxed);
-            write_file = false;
+            write_file = save_on_exit;
             break;
         }
 
-        let has_new_scanline = shared_scanline_rendered.load(std::sync::atomic::Ordering::Relaxed);
-        if (has_new_scanline) {
-            // Lock image
-            let locked_image = shared_image.lock().unwrap();
-            // Get pixels
-            let pixels = locked_image.get_pixels();
-            // Cast from &Vec<u8> to &[u32]
-            let u32_pixels = unsafe { std::slice::from_raw_parts(pixels.as_ptr() as *const u32, (WINDOW_WIDTH * WINDOW_HEIGHT * 4) as usize) };
+        if (window.is_key_pressed(minifb::Key::P, minifb::KeyRepeat::No)) {
+            save_film(&shared_film.lock().unwrap(), &shared_scene.read().unwrap().camera);
+        }
+
+        if (window.is_key_pressed(minifb::Key::O, minifb::KeyRepeat::No)) {
+            save_scene(&shared_scene.read().unwrap(), &scene_path);
+        }
 
-            window.update_with_buffer(u32_pixels, WINDOW_WIDTH as usize, WINDOW_HEIGHT as usize).unwrap();
+        let dt = last_frame.elapsed().as_secs_f32();
+        last_frame = std::time::Instant::now();
+
+        // Restart if the camera moved or the scene file changed
+        let mut restart = false;
+        {
+            // Bump the generation first so workers drop what they're tracing and release the scene
+            let mut camera = shared_scene.read().unwrap().camera;
+            let mut camera_changed = update_camera(&window, &mut camera, dt);
+
+            // F focuses on whatever is under the mouse
+            if (window.is_key_pressed(minifb::Key::F, minifb::KeyRepeat::No)) {
+                if let Some((mouse_x, mouse_y)) = window.get_mouse_pos(minifb::MouseMode::Discard) {
+                    let ray = camera.generate_ray(vec2(mouse_x / (image_width as f32), mouse_y / (image_height as f32)));
+                    if let Some(hit) = ray.and_then(|ray| shared_scene.read().unwrap().trace_closest_hit(ray)) {
+                        camera.focus_on(hit.P);
+                        camera_changed = true;
+                    }
+                }
+            }
 
-            shared_scanline_rendered.store(false, std::sync::atomic::Ordering::Relaxed);
+            if (camera_changed) {
+                shared_generation.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                shared_scene.write().unwrap().camera = camera;
+                restart = true;
+            }
         }
-        else {
-            window.update();
+
+        if (last_file_check.elapsed().as_secs_f32() > 0.5) {
+            last_file_check = std::time::Instant::now();
+            let modified_time = get_modified_time(&scene_path);
+            if (modified_time != scene_modified_time) {
+                scene_modified_time = modified_time;
+                if let Some(scene) = load_scene(&scene_path, aspect_ratio) {
+                    println!("Reloaded {}", scene_path.as_deref().unwrap_or(""));
+                    pass_count = scene.get_samples_per_pixel();
+                    shared_generation.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    *shared_scene.write().unwrap() = scene;
+                    restart = true;
+                }
+            }
         }
 
-        if (scanline_queue.lock().unwrap().is_empty()) {
-            if (!wrote_time) {
-                println!("Ray trace took: {} seconds", timer.elapsed().as_secs_f32());
-                wrote_time = true;
+        if (restart) {
+            generation = shared_generation.load(std::sync::atomic::Ordering::Relaxed);
+            scanline_queue.lock().unwrap().clear();
+            {
+                let mut film = shared_film.lock().unwrap();
+                film.clear();
+                shared_scanlines_done.store(0, std::sync::atomic::Ordering::Relaxed);
             }
+            passes_done = 0;
+            timer = std::time::Instant::now();
+            queue_pass(&scanline_queue, generation, 0, image_height);
+        }
+
+        // Start the next pass once every scanline of the current one is in the film
+        if (shared_scanlines_done.load(std::sync::atomic::Ordering::Relaxed) == image_height) {
+            shared_scanlines_done.store(0, std::sync::atomic::Ordering::Relaxed);
+            passes_done += 1;
+            if (passes_done < pass_count) {
+                queue_pass(&scanline_queue, generation, passes_done, image_height);
+            }
+            else {
+                render_seconds = timer.elapsed().as_secs_f64();
+                println!("Ray trace took: {} seconds", render_seconds);
+            }
+        }
+
+        if (last_title_update.elapsed().as_secs_f32() > 0.25) {
+            last_title_update = std::time::Instant::now();
+            let scanlines = (passes_done * image_height) + shared_scanlines_done.load(std::sync::atomic::Ordering::Relaxed);
+            let samples = (scanlines as f64) * (image_width as f64);
+            let done = passes_done >= pass_count;
+            let seconds = if (done) { render_seconds } else { timer.elapsed().as_secs_f64() };
+            let samples_per_sec = samples / seconds.max(1e-3);
+            let status = if (done) { " (done)" } else { "" };
+            window.set_title(&format!("{} - pass {}/{}{} - {:.2}M samples/sec", file!(), passes_done, pass_count, status, samples_per_sec / 1.0e6));
+        }
+
+        if (shared_film_updated.swap(false, std::sync::atomic::Ordering::Relaxed)) {
+            shared_film.lock().unwrap().write_rgb32(&mut pixels);
+            window.update_with_buffer(&pixels, WINDOW_WIDTH as usize, WINDOW_HEIGHT as usize).unwrap();
+        }
+        else {
+            window.update();
         }
     }
 
     // -------------------------------------------------------------------------
 
+    shared_stop_render.store(true, std::sync::atomic::Ordering::Relaxed);
     for thread in threads {
         thread.join().unwrap();
     }
 
     if (write_file) {
-        shared_image.lock().unwrap().swap_red_and_blue();
-        shared_image.lock().unwrap().write_png("part_7_ray_trace_primitives.png");
+        save_film(&shared_film.lock().unwrap(), &shared_scene.read().unwrap().camera);
     }
 }
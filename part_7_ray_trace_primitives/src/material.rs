@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use crate::microfacet;
+use crate::texture::TextureId;
+use crate::vec3::*;
+
+// Index into Scene::materials
+pub type MaterialId = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShadingModel {
+    Phong, // specular, shininess and a mirror reflectivity
+    Pbr,   // Metallic/roughness microfacet BRDF, see microfacet.rs
+}
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name              : String,
+    pub model             : ShadingModel,
+    pub albedo            : Vec3, // Diffuse color, or base color for Pbr
+    pub specular          : Vec3, // Phong only
+    pub shininess         : f32,  // Phong exponent
+    pub reflectivity      : f32,  // Weight of the mirror reflection, 0 is matte and 1 is a perfect mirror. Phong only
+    pub metallic          : f32,  // Pbr, 0 is a dielectric and 1 a metal
+    pub roughness         : f32,  // Pbr, 0 is smooth and 1 fully rough
+    pub emission          : Vec3,
+    pub transparency      : f32,  // Weight of the refracted ray, 0 is opaque and 1 is clear glass
+    pub ior               : f32,  // Index of refraction
+    pub absorption        : Vec3, // Beer-Lambert coefficients, per unit of distance travelled inside
+    pub light_samples     : u32,  // Shadow rays per shaded point when primitives using an emissive material act as lights, 0 turns that off
+    pub albedo_texture    : Option<TextureId>, // Multiplies albedo
+    pub roughness_texture : Option<TextureId>, // Overrides roughness, and shininess for Phong
+    pub metallic_texture  : Option<TextureId>, // Overrides metallic
+    pub emission_texture  : Option<TextureId>, // Multiplies emission
+    pub normal_texture    : Option<TextureId>, // Tangent space normal map
+    pub bump_texture      : Option<TextureId>, // Height map, grayscale
+    pub bump_scale        : f32,               // Height of a bump texture value of 1, negative to invert
+}
+
+// Used when a primitive references a material that doesn't exist
+pub static DEFAULT_MATERIAL: Material = Material {
+    name              : String::new(),
+    model             : ShadingModel::Phong,
+    albedo            : Vec3 { x: 0.8, y: 0.8, z: 0.8 },
+    specular          : Vec3 { x: 0.3, y: 0.3, z: 0.3 },
+    shininess         : 30.0,
+    reflectivity      : 0.0,
+    metallic          : 0.0,
+    roughness         : 0.5,
+    emission          : Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+    transparency      : 0.0,
+    ior               : 1.5,
+    absorption        : Vec3 { x: 0.0, y: 0.0, z: 0.0 },
+    light_samples     : 0,
+    albedo_texture    : None,
+    roughness_texture : None,
+    metallic_texture  : None,
+    emission_texture  : None,
+    normal_texture    : None,
+    bump_texture      : None,
+    bump_scale        : 0.05,
+};
+
+impl Default for Material {
+    fn default() -> Self {
+        DEFAULT_MATERIAL.clone()
+    }
+}
+
+impl Material {
+    pub fn is_emissive(&self) -> bool {
+        (self.emission.x > 0.0) || (self.emission.y > 0.0) || (self.emission.z > 0.0)
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparency > 0.0
+    }
+
+    // Primitives with this material are sampled like area lights
+    pub fn is_light(&self) -> bool {
+        self.is_emissive() && (self.light_samples > 0)
+    }
+
+    // Pbr materials reflect through their specular lobe instead
+    pub fn get_mirror_reflectivity(&self) -> f32 {
+        match self.model {
+            ShadingModel::Phong => self.reflectivity,
+            ShadingModel::Pbr => 0.0,
+        }
+    }
+
+    // Textures that change the material's values, normal and bump maps
+    // change the hit instead
+    pub fn has_textures(&self) -> bool {
+        self.albedo_texture.is_some() || self.roughness_texture.is_some() || self.metallic_texture.is_some() || self.emission_texture.is_some()
+    }
+}
+
+// Phong exponent giving about the same highlight as a microfacet roughness,
+// with alpha = roughness^2
+// https://simonstechblog.blogspot.com/2011/12/microfacet-brdf.html
+pub fn roughness_to_shininess(roughness: f32) -> f32 {
+    let alpha = microfacet::roughness_to_alpha(roughness);
+    (2.0 / (alpha * alpha)) - 2.0
+}
+
+// Unpolarized Fresnel reflectance at a dielectric boundary. cos_i is the
+// cosine of the incident angle and eta the incident over the transmitted
+// index of refraction. Returns 1 on total internal reflection.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * ((r_s * r_s) + (r_p * r_p))
+}
@@ -0,0 +1,315 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use std::any::Any;
+
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::hit::{hit_record, HitRecord};
+use crate::material::MaterialId;
+use crate::primitives::Primitive;
+use crate::ray::Ray;
+use crate::transform::Transform;
+use crate::vec2::{vec2, Vec2};
+use crate::vec3;
+use crate::vec3::*;
+
+pub const INVALID_INDEX: u32 = u32::MAX;
+
+// Indices into MeshData's positions, normals and uvs for each corner. Normal
+// and UV indices are INVALID_INDEX when the source didn't provide them.
+#[derive(Debug, Copy, Clone)]
+pub struct Triangle {
+    pub position : [u32; 3],
+    pub normal   : [u32; 3],
+    pub uv       : [u32; 3],
+}
+
+#[derive(Default)]
+pub struct MeshData {
+    pub positions : Vec<Vec3>,
+    pub normals   : Vec<Vec3>,
+    pub uvs       : Vec<Vec2>,
+    pub triangles : Vec<Triangle>,
+}
+
+// =====================================================================================================================
+// TriangleMesh
+// =====================================================================================================================
+pub struct TriangleMesh {
+    pub transform : Transform,
+    pub material  : MaterialId,
+    pub file_path : Option<String>, // Source file, if loaded from one
+    data          : MeshData,
+    bvh           : Bvh,
+}
+
+impl TriangleMesh {
+    pub fn new(transform: Transform, mut data: MeshData, material: MaterialId) -> TriangleMesh {
+        compute_missing_normals(&mut data);
+
+        let bounds: Vec<Aabb> = data.triangles.iter().map(|tri| {
+            let mut b = Aabb::empty();
+            for i in 0..3 {
+                b.grow(data.positions[tri.position[i] as usize]);
+            }
+            b
+        }).collect();
+        let bvh = Bvh::build(&bounds);
+
+        TriangleMesh {
+            transform,
+            material,
+            file_path: None,
+            data,
+            bvh,
+        }
+    }
+
+    pub fn get_data(&self) -> &MeshData {
+        &self.data
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.data.triangles.len()
+    }
+
+    fn get_normal(&self, transform: &Transform, triangle_index: usize, b1: f32, b2: f32) -> Vec3 {
+        let tri = &self.data.triangles[triangle_index];
+        let n0 = self.data.normals[tri.normal[0] as usize];
+        let n1 = self.data.normals[tri.normal[1] as usize];
+        let n2 = self.data.normals[tri.normal[2] as usize];
+        let N = ((1.0 - b1 - b2) * n0) + (b1 * n1) + (b2 * n2);
+        transform.local_to_world_normal(N)
+    }
+
+    // World space normal of the triangle's plane, on the same side as the
+    // vertex normals since either winding order is accepted
+    fn get_face_normal(&self, transform: &Transform, triangle_index: usize) -> Vec3 {
+        let tri = &self.data.triangles[triangle_index];
+        let p0 = self.data.positions[tri.position[0] as usize];
+        let p1 = self.data.positions[tri.position[1] as usize];
+        let p2 = self.data.positions[tri.position[2] as usize];
+        let N = vec3::cross(p1 - p0, p2 - p0);
+        let vertex_N = self.data.normals[tri.normal[0] as usize] + self.data.normals[tri.normal[1] as usize] + self.data.normals[tri.normal[2] as usize];
+        let N = if (vec3::dot(N, vertex_N) < 0.0) { -N } else { N };
+        transform.local_to_world_normal(N)
+    }
+
+    // Interpolated UV and the world space direction u increases in, if the
+    // triangle has UVs
+    fn get_uv(&self, transform: &Transform, triangle_index: usize, b1: f32, b2: f32) -> Option<(Vec2, Vec3)> {
+        let tri = &self.data.triangles[triangle_index];
+        if tri.uv.contains(&INVALID_INDEX) {
+            return None;
+        }
+        let uv0 = self.data.uvs[tri.uv[0] as usize];
+        let uv1 = self.data.uvs[tri.uv[1] as usize];
+        let uv2 = self.data.uvs[tri.uv[2] as usize];
+        let uv = (uv0 * (1.0 - b1 - b2)) + (uv1 * b1) + (uv2 * b2);
+
+        // Solve dp1 = du1*dpdu + dv1*dpdv, dp2 = du2*dpdu + dv2*dpdv for dpdu
+        let p0 = self.data.positions[tri.position[0] as usize];
+        let dp1 = self.data.positions[tri.position[1] as usize] - p0;
+        let dp2 = self.data.positions[tri.position[2] as usize] - p0;
+        let duv1 = uv1 - uv0;
+        let duv2 = uv2 - uv0;
+        let det = (duv1.x * duv2.y) - (duv1.y * duv2.x);
+        let dpdu = if (det != 0.0) { ((duv2.y * dp1) - (duv1.y * dp2)) / det } else { vec3::ZERO };
+        Some((uv, transform.local_to_world_vector(dpdu)))
+    }
+}
+
+// Area weighted vertex normals for any corner that has no normal. These are
+// appended after the source normals and indexed by position index.
+fn compute_missing_normals(data: &mut MeshData) {
+    let missing = data.triangles.iter().any(|tri| tri.normal.contains(&INVALID_INDEX));
+    if !missing {
+        return;
+    }
+
+    let mut vertex_normals = vec![vec3::ZERO; data.positions.len()];
+    for tri in &data.triangles {
+        let p0 = data.positions[tri.position[0] as usize];
+        let p1 = data.positions[tri.position[1] as usize];
+        let p2 = data.positions[tri.position[2] as usize];
+        // Length of the cross product is twice the area
+        let face_normal = vec3::cross(p1 - p0, p2 - p0);
+        for i in 0..3 {
+            vertex_normals[tri.position[i] as usize] += face_normal;
+        }
+    }
+
+    let base = data.normals.len() as u32;
+    for N in vertex_normals {
+        let len = vec3::length(N);
+        data.normals.push(if (len > 0.0) { N / len } else { vec3::Y_AXIS });
+    }
+
+    for tri in data.triangles.iter_mut() {
+        for i in 0..3 {
+            if (tri.normal[i] == INVALID_INDEX) {
+                tri.normal[i] = base + tri.position[i];
+            }
+        }
+    }
+}
+
+// Per ray setup for the watertight ray/triangle test
+// http://jcgt.org/published/0002/01/05/
+struct WatertightRay {
+    org : Vec3,
+    kx  : usize,
+    ky  : usize,
+    kz  : usize,
+    Sx  : f32,
+    Sy  : f32,
+    Sz  : f32,
+}
+
+impl WatertightRay {
+    fn new(ray: &Ray) -> WatertightRay {
+        let d = ray.dir;
+        let ad = vec3::abs(d);
+
+        // Dimension where the ray direction is maximal
+        let kz = if (ad.x > ad.y) { if (ad.x > ad.z) { 0 } else { 2 } } else if (ad.y > ad.z) { 1 } else { 2 };
+        let mut kx = if (kz + 1 == 3) { 0 } else { kz + 1 };
+        let mut ky = if (kx + 1 == 3) { 0 } else { kx + 1 };
+
+        // Swap to preserve winding
+        if (d[kz] < 0.0) {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        WatertightRay {
+            org: ray.pos,
+            kx,
+            ky,
+            kz,
+            Sx: d[kx] / d[kz],
+            Sy: d[ky] / d[kz],
+            Sz: 1.0 / d[kz],
+        }
+    }
+
+    // Returns t and the barycentrics of p1 and p2
+    fn intersect(&self, p0: Vec3, p1: Vec3, p2: Vec3) -> Option<(f32, f32, f32)> {
+        let A = p0 - self.org;
+        let B = p1 - self.org;
+        let C = p2 - self.org;
+
+        // Shear and scale vertices
+        let Ax = A[self.kx] - self.Sx * A[self.kz];
+        let Ay = A[self.ky] - self.Sy * A[self.kz];
+        let Bx = B[self.kx] - self.Sx * B[self.kz];
+        let By = B[self.ky] - self.Sy * B[self.kz];
+        let Cx = C[self.kx] - self.Sx * C[self.kz];
+        let Cy = C[self.ky] - self.Sy * C[self.kz];
+
+        // Scaled barycentric coordinates
+        let mut U = (Cx * By) - (Cy * Bx);
+        let mut V = (Ax * Cy) - (Ay * Cx);
+        let mut W = (Bx * Ay) - (By * Ax);
+
+        // Fall back to double precision on edges
+        if (U == 0.0) || (V == 0.0) || (W == 0.0) {
+            U = ((Cx as f64 * By as f64) - (Cy as f64 * Bx as f64)) as f32;
+            V = ((Ax as f64 * Cy as f64) - (Ay as f64 * Cx as f64)) as f32;
+            W = ((Bx as f64 * Ay as f64) - (By as f64 * Ax as f64)) as f32;
+        }
+
+        // Both winding orders are accepted
+        if ((U < 0.0) || (V < 0.0) || (W < 0.0)) && ((U > 0.0) || (V > 0.0) || (W > 0.0)) {
+            return None;
+        }
+
+        let det = U + V + W;
+        if (det == 0.0) {
+            return None;
+        }
+
+        let Az = self.Sz * A[self.kz];
+        let Bz = self.Sz * B[self.kz];
+        let Cz = self.Sz * C[self.kz];
+        let T = (U * Az) + (V * Bz) + (W * Cz);
+
+        let inv_det = 1.0 / det;
+        let t = T * inv_det;
+        Some((t, V * inv_det, W * inv_det))
+    }
+}
+
+impl TriangleMesh {
+    fn intersect_triangle(&self, wray: &WatertightRay, triangle_index: usize) -> Option<(f32, f32, f32)> {
+        let tri = &self.data.triangles[triangle_index];
+        let p0 = self.data.positions[tri.position[0] as usize];
+        let p1 = self.data.positions[tri.position[1] as usize];
+        let p2 = self.data.positions[tri.position[2] as usize];
+        wray.intersect(p0, p1, p2)
+    }
+}
+
+impl Primitive for TriangleMesh {
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
+
+        let wray = WatertightRay::new(&local_ray);
+        let mut hit_triangle = usize::MAX;
+        let mut hit_t = 0.0;
+        let mut hit_b1 = 0.0;
+        let mut hit_b2 = 0.0;
+        let hit = self.bvh.closest_hit(&local_ray, |i, closest_t| {
+            match self.intersect_triangle(&wray, i) {
+                Some((t, b1, b2)) if (t > local_ray.t_min) && (t < closest_t) => {
+                    hit_triangle = i;
+                    hit_t = t;
+                    hit_b1 = b1;
+                    hit_b2 = b2;
+                    Some(t)
+                },
+                _ => None,
+            }
+        });
+
+        if (!hit) {
+            return None;
+        }
+
+        let mut record = hit_record(ray, hit_t, self.get_face_normal(&transform, hit_triangle), self.material);
+        record.set_shading_normal(self.get_normal(&transform, hit_triangle, hit_b1, hit_b2));
+        match self.get_uv(&transform, hit_triangle, hit_b1, hit_b2) {
+            Some((uv, dpdu)) => record.set_uv(uv, dpdu),
+            None => record.uv = vec2(hit_b1, hit_b2), // Barycentrics when there are no UVs
+        }
+        Some(record)
+    }
+
+    fn intersect_shadow(&self, ray: &Ray) -> bool {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
+
+        let wray = WatertightRay::new(&local_ray);
+        self.bvh.any_hit(&local_ray, |i| {
+            matches!(self.intersect_triangle(&wray, i), Some((t, _, _)) if local_ray.contains(t))
+        })
+    }
+
+    fn get_material(&self) -> MaterialId {
+        self.material
+    }
+
+    fn get_transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        self.bvh.get_bounds()
+    }
+}
@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+use crate::material::Material;
+use crate::sampling;
+use crate::vec3;
+use crate::vec3::*;
+
+// Metallic/roughness BRDF for ShadingModel::Pbr materials: a GGX specular
+// lobe with Smith masking-shadowing and Schlick Fresnel over a Lambert
+// diffuse base. Metals tint the specular with albedo and have no diffuse,
+// dielectrics reflect what the Fresnel term leaves to the diffuse.
+// https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
+
+// Roughness below this is a mirror as far as floats are concerned
+const MIN_ROUGHNESS: f32 = 0.05;
+
+// GGX alpha for a perceptual 0-1 roughness
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    let roughness = roughness.clamp(MIN_ROUGHNESS, 1.0);
+    roughness * roughness
+}
+
+// GGX (Trowbridge-Reitz) distribution of microfacet normals
+pub fn ggx_distribution(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = (cos_h * cos_h * (a2 - 1.0)) + 1.0;
+    a2 / (PI * d * d)
+}
+
+// Smith masking for one direction with the GGX distribution
+pub fn smith_g1(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    (2.0 * cos_theta) / (cos_theta + (a2 + (1.0 - a2) * cos_theta * cos_theta).sqrt())
+}
+
+pub fn fresnel_schlick(F0: Vec3, cos_theta: f32) -> Vec3 {
+    F0 + ((vec3::ONE - F0) * (1.0 - cos_theta).clamp(0.0, 1.0).powi(5))
+}
+
+// Reflectance at normal incidence, from ior for dielectrics and albedo for
+// metals
+pub fn get_F0(material: &Material) -> Vec3 {
+    let r = (material.ior - 1.0) / (material.ior + 1.0);
+    vec3::mix(vec3::from_scalar(r * r), material.albedo, material.metallic.clamp(0.0, 1.0))
+}
+
+// BRDF of the specular lobe only
+pub fn eval_specular(material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+    let cos_v = dot(N, V);
+    let cos_l = dot(N, L);
+    if (cos_v <= 0.0) || (cos_l <= 0.0) {
+        return vec3::ZERO;
+    }
+    let H = normalize(V + L);
+    let alpha = roughness_to_alpha(material.roughness);
+    let F = fresnel_schlick(get_F0(material), dot(V, H));
+    let D = ggx_distribution(dot(N, H), alpha);
+    let G = smith_g1(cos_v, alpha) * smith_g1(cos_l, alpha);
+    F * (D * G / (4.0 * cos_v * cos_l))
+}
+
+// Lambert diffuse part of eval()
+pub fn eval_diffuse(material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+    if (dot(N, V) <= 0.0) || (dot(N, L) <= 0.0) {
+        return vec3::ZERO;
+    }
+    let F = fresnel_schlick(get_F0(material), dot(N, V));
+    (1.0 - material.metallic.clamp(0.0, 1.0)) * (vec3::ONE - F) * (material.albedo / PI)
+}
+
+// Full BRDF. The diffuse only gets the light the specular Fresnel doesn't
+// reflect towards V, so the two together never reflect more than arrives.
+pub fn eval(material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+    eval_diffuse(material, N, V, L) + eval_specular(material, N, V, L)
+}
+
+// Pdf of L when the half vector is sampled from D(H) cos(H), over the
+// Jacobian of reflecting V about it
+fn specular_pdf(material: &Material, N: Vec3, V: Vec3, L: Vec3) -> f32 {
+    let H = normalize(V + L);
+    let cos_h = dot(N, H);
+    let v_dot_h = dot(V, H);
+    if (cos_h <= 0.0) || (v_dot_h <= 0.0) {
+        return 0.0;
+    }
+    ggx_distribution(cos_h, roughness_to_alpha(material.roughness)) * cos_h / (4.0 * v_dot_h)
+}
+
+// Probability of sampling the specular lobe, from how much of the light
+// each lobe reflects straight back towards V
+fn specular_probability(material: &Material, N: Vec3, V: Vec3) -> f32 {
+    let specular = sampling::luminance(fresnel_schlick(get_F0(material), dot(N, V)));
+    let diffuse = (1.0 - material.metallic.clamp(0.0, 1.0)) * sampling::luminance(material.albedo) * (1.0 - specular);
+    if (specular + diffuse <= 0.0) {
+        return 1.0;
+    }
+    specular / (specular + diffuse)
+}
+
+// Mirror direction of V about a GGX distributed microfacet normal
+fn sample_specular_direction(material: &Material, N: Vec3, V: Vec3, u1: f32, u2: f32) -> Vec3 {
+    let alpha = roughness_to_alpha(material.roughness);
+    let H = sampling::local_to_world(sampling::ggx_sample_half(u1, u2, alpha), N);
+    reflect(-V, H)
+}
+
+// Pdf of sample() returning L
+pub fn pdf(material: &Material, N: Vec3, V: Vec3, L: Vec3) -> f32 {
+    let cos_theta = dot(N, L);
+    if (dot(N, V) <= 0.0) || (cos_theta <= 0.0) {
+        return 0.0;
+    }
+    let specular_prob = specular_probability(material, N, V);
+    ((1.0 - specular_prob) * sampling::cosine_hemisphere_pdf(cos_theta)) + (specular_prob * specular_pdf(material, N, V, L))
+}
+
+// Picks the diffuse or the specular lobe and samples a direction from it.
+// Returns the direction, the BRDF times cosine over the combined pdf and the
+// pdf, or None if the sample is below the surface.
+pub fn sample(material: &Material, N: Vec3, V: Vec3, u1: f32, u2: f32, u3: f32) -> Option<(Vec3, Vec3, f32)> {
+    if (dot(N, V) <= 0.0) {
+        return None;
+    }
+    let specular_prob = specular_probability(material, N, V);
+    let L = if (u3 < specular_prob) {
+        sample_specular_direction(material, N, V, u1, u2)
+    }
+    else {
+        sampling::local_to_world(sampling::cosine_sample_hemisphere(u1, u2), N)
+    };
+
+    let cos_theta = dot(N, L);
+    if (cos_theta <= 0.0) {
+        return None;
+    }
+
+    let pdf = pdf(material, N, V, L);
+    if (pdf <= 0.0) {
+        return None;
+    }
+
+    Some((L, eval(material, N, V, L) * (cos_theta / pdf), pdf))
+}
+
+// Samples the specular lobe alone, for glossy reflections in the Whitted
+// integrator. Returns the direction and the specular BRDF times cosine over
+// the pdf.
+pub fn sample_specular(material: &Material, N: Vec3, V: Vec3, u1: f32, u2: f32) -> Option<(Vec3, Vec3)> {
+    if (dot(N, V) <= 0.0) {
+        return None;
+    }
+    let L = sample_specular_direction(material, N, V, u1, u2);
+    let cos_theta = dot(N, L);
+    let pdf = specular_pdf(material, N, V, L);
+    if (cos_theta <= 0.0) || (pdf <= 0.0) {
+        return None;
+    }
+
+    Some((L, eval_specular(material, N, V, L) * (cos_theta / pdf)))
+}
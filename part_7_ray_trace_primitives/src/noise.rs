@@ -0,0 +1,95 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use crate::vec3::*;
+
+// Gradient noise for procedural textures. The lattice gradients come from
+// hashing the cell coordinates instead of a permutation table.
+// https://mrl.cs.nyu.edu/~perlin/paper445.pdf
+
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^ (h >> 16)
+}
+
+// Dot product of one of the 12 edge directions of a cube with (x, y, z)
+fn grad(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    match h % 12 {
+        0  =>  x + y,
+        1  => -x + y,
+        2  =>  x - y,
+        3  => -x - y,
+        4  =>  x + z,
+        5  => -x + z,
+        6  =>  x - z,
+        7  => -x - z,
+        8  =>  y + z,
+        9  => -y + z,
+        10 =>  y - z,
+        _  => -y - z,
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + t * (b - a)
+}
+
+// Roughly -1 to 1, zero at every integer point
+pub fn perlin(P: Vec3) -> f32 {
+    let cell = vec3(P.x.floor(), P.y.floor(), P.z.floor());
+    let (ix, iy, iz) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let f = P - cell;
+    let u = fade(f.x);
+    let v = fade(f.y);
+    let w = fade(f.z);
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        grad(hash(ix + dx, iy + dy, iz + dz), f.x - dx as f32, f.y - dy as f32, f.z - dz as f32)
+    };
+
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w,
+    )
+}
+
+// Fractal Brownian motion, octaves of noise each at twice the frequency and
+// half the amplitude of the last. Normalized back to about -1 to 1.
+pub fn fbm(P: Vec3, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut P = P;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * perlin(P);
+        total += amplitude;
+        amplitude *= 0.5;
+        P = 2.0 * P;
+    }
+    sum / total
+}
+
+// fBm of the absolute value of the noise, 0 to about 1 with sharp creases
+pub fn turbulence(P: Vec3, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut P = P;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * perlin(P).abs();
+        total += amplitude;
+        amplitude *= 0.5;
+        P = 2.0 * P;
+    }
+    sum / total
+}
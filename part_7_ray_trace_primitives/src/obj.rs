@@ -0,0 +1,222 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::material::MaterialId;
+use crate::mesh::{MeshData, Triangle, TriangleMesh, INVALID_INDEX};
+use crate::transform::Transform;
+use crate::vec2::vec2;
+use crate::vec3::*;
+
+// Wavefront OBJ loader. Reads positions (v), normals (vn), texture coordinates
+// (vt) and faces (f). Faces with more than 3 vertices are triangulated as a fan
+// around the first vertex. Vertex colors or a w after a position are skipped.
+// Groups, smoothing groups, materials and other statements are ignored.
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(err) => write!(f, "{}", err),
+            ObjError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ObjError {
+    fn from(err: std::io::Error) -> Self {
+        ObjError::Io(err)
+    }
+}
+
+pub fn load_obj(file_path: &str) -> Result<MeshData, ObjError> {
+    let file = File::open(file_path)?;
+    parse_obj(BufReader::new(file))
+}
+
+pub fn load_triangle_mesh(file_path: &str, transform: Transform, material: MaterialId) -> Result<TriangleMesh, ObjError> {
+    let data = load_obj(file_path)?;
+    let mut mesh = TriangleMesh::new(transform, data, material);
+    mesh.file_path = Some(file_path.to_string());
+    Ok(mesh)
+}
+
+pub fn parse_obj<R: BufRead>(reader: R) -> Result<MeshData, ObjError> {
+    let mut data = MeshData::default();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_number = line_index + 1;
+        let error = |message: String| ObjError::Parse { line: line_number, message };
+
+        // Strip comments
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => &line[..],
+        };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => {
+                let v = parse_floats(&args, 3, 7).map_err(error)?;
+                data.positions.push(vec3(v[0], v[1], v[2]));
+            },
+            "vn" => {
+                let v = parse_floats(&args, 3, 3).map_err(error)?;
+                data.normals.push(normalize(vec3(v[0], v[1], v[2])));
+            },
+            "vt" => {
+                let v = parse_floats(&args, 1, 3).map_err(error)?;
+                data.uvs.push(vec2(v[0], if (v.len() > 1) { v[1] } else { 0.0 }));
+            },
+            "f" => {
+                if (args.len() < 3) {
+                    return Err(error(format!("face needs at least 3 vertices, found {}", args.len())));
+                }
+
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in &args {
+                    corners.push(parse_face_vertex(arg, &data).map_err(error)?);
+                }
+
+                // Fan triangulation
+                for i in 1..(corners.len() - 1) {
+                    let (c0, c1, c2) = (corners[0], corners[i], corners[i + 1]);
+                    data.triangles.push(Triangle {
+                        position : [c0.0, c1.0, c2.0],
+                        uv       : [c0.1, c1.1, c2.1],
+                        normal   : [c0.2, c1.2, c2.2],
+                    });
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(data)
+}
+
+fn parse_floats(args: &[&str], min_count: usize, max_count: usize) -> Result<Vec<f32>, String> {
+    if (args.len() < min_count) || (args.len() > max_count) {
+        if (min_count == max_count) {
+            return Err(format!("expected {} values, found {}", min_count, args.len()));
+        }
+        return Err(format!("expected {} to {} values, found {}", min_count, max_count, args.len()));
+    }
+
+    args.iter()
+        .map(|arg| arg.parse::<f32>().map_err(|_| format!("invalid number '{}'", arg)))
+        .collect()
+}
+
+// Parses v, v/vt, v//vn or v/vt/vn and returns zero based (position, uv, normal)
+// indices. Negative indices are relative to the end of the lists read so far.
+fn parse_face_vertex(arg: &str, data: &MeshData) -> Result<(u32, u32, u32), String> {
+    let mut parts = arg.split('/');
+    let position = parse_index(parts.next(), data.positions.len(), "position", arg)?;
+    let uv = parse_index(parts.next(), data.uvs.len(), "texture coordinate", arg)?;
+    let normal = parse_index(parts.next(), data.normals.len(), "normal", arg)?;
+    if (parts.next().is_some()) {
+        return Err(format!("invalid face vertex '{}'", arg));
+    }
+    if (position == INVALID_INDEX) {
+        return Err(format!("face vertex '{}' has no position index", arg));
+    }
+    Ok((position, uv, normal))
+}
+
+fn parse_index(part: Option<&str>, count: usize, name: &str, arg: &str) -> Result<u32, String> {
+    let part = match part {
+        Some(part) if !part.is_empty() => part,
+        _ => return Ok(INVALID_INDEX),
+    };
+
+    let index = part.parse::<i64>().map_err(|_| format!("invalid {} index in '{}'", name, arg))?;
+    let resolved = if (index > 0) {
+        index - 1
+    }
+    else if (index < 0) {
+        (count as i64) + index
+    }
+    else {
+        return Err(format!("{} index can't be 0 in '{}'", name, arg));
+    };
+
+    if (resolved < 0) || (resolved >= count as i64) {
+        return Err(format!("{} index {} out of range in '{}' ({} defined)", name, index, arg, count));
+    }
+
+    Ok(resolved as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::Primitive;
+    use crate::ray;
+
+    // A unit quad in the XY plane, the second vertex with a vertex color
+    const QUAD: &str = "
+        # quad
+        v 0 0 0
+        v 1 0 0 1 0.5 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vn 0 0 -1
+        f -4/1/1 -3/1/1 -2/1/1 -1/1/1
+    ";
+
+    #[test]
+    fn parses_and_fans_a_quad() {
+        let data = parse_obj(QUAD.as_bytes()).unwrap();
+        assert_eq!(data.positions.len(), 4);
+        assert_eq!(data.triangles.len(), 2);
+        assert_eq!(data.triangles[0].position, [0, 1, 2]);
+        assert_eq!(data.triangles[1].position, [0, 2, 3]);
+        assert_eq!(data.triangles[1].uv, [0, 0, 0]);
+        assert_eq!(data.triangles[1].normal, [0, 0, 0]);
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        let cases = [
+            ("v 0 0 0\nv 1 0 0\nf 1 2\n", 3),
+            ("v 0 0 0\n\nv 1 x 0\n", 3),
+            ("v 0 0 0\nv 1 0 0\nv 1 1 0\n# comment\nf 1 2 4\n", 5),
+            ("v 0 0\n", 1),
+        ];
+        for (text, expected_line) in cases {
+            match parse_obj(text.as_bytes()) {
+                Err(ObjError::Parse { line, .. }) => assert_eq!(line, expected_line, "{:?}", text),
+                _ => panic!("expected a parse error for {:?}", text),
+            }
+        }
+    }
+
+    #[test]
+    fn rays_along_the_shared_edge_hit() {
+        let mesh = TriangleMesh::new(Transform::new(), parse_obj(QUAD.as_bytes()).unwrap(), 0);
+        for i in 1..100 {
+            let a = (i as f32) / 100.0;
+            let ray = ray::ray(vec3(a, a, -1.0), vec3(0.0, 0.0, 1.0));
+            let hit = mesh.intersect_illum(&ray).unwrap_or_else(|| panic!("missed at {}", a));
+            assert!((hit.t - 1.0).abs() < 1e-5);
+            assert!(mesh.intersect_shadow(&ray));
+        }
+    }
+}
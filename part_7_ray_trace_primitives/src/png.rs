@@ -0,0 +1,469 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+// Minimal PNG decoder for loading textures. Handles every color type and bit
+// depth in the spec but not interlaced images. Ancillary chunks, including
+// gamma and transparency, are ignored and checksums aren't verified.
+// https://www.w3.org/TR/png/
+// https://www.rfc-editor.org/rfc/rfc1951 (DEFLATE)
+
+use crate::image::MAX_IMAGE_SIZE;
+
+// 8 bit RGB, 3 bytes per pixel with rows from the top down
+pub struct DecodedPng {
+    pub width  : u32,
+    pub height : u32,
+    pub rgb    : Vec<u8>,
+}
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+pub fn decode_png(data: &[u8]) -> Result<DecodedPng, String> {
+    if (data.len() < 8) || (data[0..8] != SIGNATURE) {
+        return Err("not a PNG file".to_string());
+    }
+
+    let mut width = 0;
+    let mut height = 0;
+    let mut bit_depth = 0;
+    let mut color_type = 0;
+    let mut palette: Vec<u8> = Vec::new();
+    let mut compressed: Vec<u8> = Vec::new();
+
+    // Chunks are a big endian length, a 4 character type, the data and a CRC
+    let mut pos = 8;
+    loop {
+        if (pos + 8 > data.len()) {
+            return Err("file ends before the IEND chunk".to_string());
+        }
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let start = pos + 8;
+        if (start + length + 4 > data.len()) {
+            return Err("chunk runs past the end of the file".to_string());
+        }
+        let chunk = &data[start..start + length];
+        pos = start + length + 4;
+
+        match chunk_type {
+            b"IHDR" => {
+                if (length != 13) {
+                    return Err("IHDR chunk has the wrong size".to_string());
+                }
+                width = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                height = u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+                bit_depth = chunk[8];
+                color_type = chunk[9];
+                if (chunk[12] != 0) {
+                    return Err("interlaced PNGs aren't supported".to_string());
+                }
+            },
+            b"PLTE" => palette = chunk.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {},
+        }
+    }
+
+    let channels = match color_type {
+        0 => 1, // Gray
+        2 => 3, // RGB
+        3 => 1, // Palette index
+        4 => 2, // Gray and alpha
+        6 => 4, // RGBA
+        _ => return Err(format!("unknown color type {}", color_type)),
+    };
+    let valid_depth = match color_type {
+        0 => matches!(bit_depth, 1 | 2 | 4 | 8 | 16),
+        3 => matches!(bit_depth, 1 | 2 | 4 | 8),
+        _ => matches!(bit_depth, 8 | 16),
+    };
+    if (!valid_depth) {
+        return Err(format!("bit depth {} isn't valid for color type {}", bit_depth, color_type));
+    }
+    if (width == 0) || (height == 0) {
+        return Err("image has no pixels".to_string());
+    }
+    if (width > MAX_IMAGE_SIZE) || (height > MAX_IMAGE_SIZE) {
+        return Err(format!("image is {}x{}, the largest supported side is {}", width, height, MAX_IMAGE_SIZE));
+    }
+    if (color_type == 3) && palette.is_empty() {
+        return Err("palette image has no PLTE chunk".to_string());
+    }
+
+    // zlib wraps the deflate stream in a 2 byte header and an Adler-32 checksum
+    if (compressed.len() < 2) || ((compressed[0] & 0x0f) != 8) || ((compressed[1] & 0x20) != 0) {
+        return Err("image data isn't a zlib stream".to_string());
+    }
+    let too_large = || "image is too large".to_string();
+    let bits_per_pixel = channels * bit_depth as usize;
+    let stride = (width as usize).checked_mul(bits_per_pixel).ok_or_else(too_large)?.div_ceil(8);
+    let expected = (stride + 1).checked_mul(height as usize).ok_or_else(too_large)?;
+    let mut raw = inflate(&compressed[2..], expected)?;
+    if (raw.len() < expected) {
+        return Err("image data is too short".to_string());
+    }
+
+    unfilter(&mut raw, stride, height as usize, bits_per_pixel.div_ceil(8))?;
+
+    // Samples scaled to 8 bits, the high byte of 16 bit ones
+    let sample = |row: &[u8], index: usize| -> u8 {
+        match bit_depth {
+            16 => row[index * 2],
+            8 => row[index],
+            _ => {
+                let per_byte = 8 / bit_depth as usize;
+                let shift = 8 - bit_depth as usize * (1 + index % per_byte);
+                let value = (row[index / per_byte] >> shift) & ((1 << bit_depth) - 1);
+                if (color_type == 3) { value } else { ((value as u32 * 255) / ((1 << bit_depth) - 1)) as u8 }
+            },
+        }
+    };
+
+    // The image data is all there so the size from the header can be trusted
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    for y in 0..height as usize {
+        let row = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        for x in 0..width as usize {
+            match color_type {
+                0 | 4 => {
+                    let gray = sample(row, x * channels);
+                    rgb.extend_from_slice(&[gray, gray, gray]);
+                },
+                3 => {
+                    let index = sample(row, x) as usize * 3;
+                    if (index + 3 > palette.len()) {
+                        return Err("palette index out of range".to_string());
+                    }
+                    rgb.extend_from_slice(&palette[index..index + 3]);
+                },
+                _ => {
+                    for c in 0..3 {
+                        rgb.push(sample(row, x * channels + c));
+                    }
+                },
+            }
+        }
+    }
+
+    Ok(DecodedPng { width, height, rgb })
+}
+
+// Undoes the per scanline filters in place. Each row starts with its filter
+// type, bpp is the number of bytes per pixel rounded up.
+fn unfilter(raw: &mut [u8], stride: usize, height: usize, bpp: usize) -> Result<(), String> {
+    for y in 0..height {
+        let (above, rest) = raw.split_at_mut(y * (stride + 1));
+        let prior = if (y > 0) { Some(&above[above.len() - stride..]) } else { None };
+        let filter = rest[0];
+        let row = &mut rest[1..stride + 1];
+
+        for x in 0..stride {
+            let a = if (x >= bpp) { row[x - bpp] as i16 } else { 0 };
+            let b = prior.map_or(0, |p| p[x] as i16);
+            let c = if (x >= bpp) { prior.map_or(0, |p| p[x - bpp] as i16) } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => (a + b) / 2,
+                4 => {
+                    // Paeth, whichever neighbor is closest to a + b - c
+                    let p = a + b - c;
+                    let pa = (p - a).abs();
+                    let pb = (p - b).abs();
+                    let pc = (p - c).abs();
+                    if (pa <= pb) && (pa <= pc) { a } else if (pb <= pc) { b } else { c }
+                },
+                _ => return Err(format!("unknown filter type {}", filter)),
+            };
+            row[x] = row[x].wrapping_add(predicted as u8);
+        }
+    }
+    Ok(())
+}
+
+// =====================================================================================================================
+// Inflate
+// =====================================================================================================================
+struct BitReader<'a> {
+    data     : &'a [u8],
+    pos      : usize,
+    bit_buf  : u32,
+    bit_count: u32,
+}
+
+impl BitReader<'_> {
+    // Next count bits, least significant first
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while (self.bit_count < count) {
+            if (self.pos >= self.data.len()) {
+                return Err("compressed data ends unexpectedly".to_string());
+            }
+            self.bit_buf |= (self.data[self.pos] as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u32 << count) - 1);
+        self.bit_buf = if (count == 32) { 0 } else { self.bit_buf >> count };
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary
+    fn align_to_byte(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+const MAX_BITS: usize = 15;
+
+// Canonical Huffman code as the number of codes of each length and the
+// symbols sorted by code
+struct Huffman {
+    counts  : [u16; MAX_BITS + 1],
+    symbols : Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if (length != 0) {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    // Codes are packed most significant bit first, so read a bit at a time
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code: i32 = 0;  // Bits read so far
+        let mut first: i32 = 0; // First code of the current length
+        let mut index: i32 = 0; // Index of that code's symbol
+        for length in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if (code - first < count) {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Order the code length code lengths are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Decompresses a raw deflate stream, stopping once max_size bytes are out
+// so corrupt data can't make it grow without bound.
+pub fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { data, pos: 0, bit_buf: 0, bit_count: 0 };
+    let mut out = Vec::new();
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                // Stored, a length, its complement and the bytes as they are
+                reader.align_to_byte();
+                if (reader.pos + 4 > data.len()) {
+                    return Err("compressed data ends unexpectedly".to_string());
+                }
+                let length = u16::from_le_bytes([data[reader.pos], data[reader.pos + 1]]) as usize;
+                let complement = u16::from_le_bytes([data[reader.pos + 2], data[reader.pos + 3]]) as usize;
+                if (length != (!complement & 0xffff)) {
+                    return Err("stored block length is corrupt".to_string());
+                }
+                reader.pos += 4;
+                if (reader.pos + length > data.len()) {
+                    return Err("compressed data ends unexpectedly".to_string());
+                }
+                let length = length.min(max_size - out.len());
+                out.extend_from_slice(&data[reader.pos..reader.pos + length]);
+                reader.pos += length;
+            },
+            1 => {
+                // Fixed Huffman codes
+                let mut lengths = [0u8; 288];
+                lengths[0..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5u8; 30]);
+                inflate_block(&mut reader, &mut out, max_size, &literals, &distances)?;
+            },
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, max_size, &literals, &distances)?;
+            },
+            _ => return Err("invalid block type".to_string()),
+        }
+        if (last || (out.len() >= max_size)) {
+            break;
+        }
+    }
+
+    Ok(out)
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    // Literal and distance code lengths are one run length encoded sequence
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut i = 0;
+    while (i < lengths.len()) {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if (i == 0) {
+                    return Err("repeated code length with nothing before it".to_string());
+                }
+                (lengths[i - 1], 3 + reader.bits(2)? as usize)
+            },
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if (i + repeat > lengths.len()) {
+            return Err("too many code lengths".to_string());
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    if (lengths[256] == 0) {
+        return Err("no end of block code".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(reader: &mut BitReader, out: &mut Vec<u8>, max_size: usize, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        if (out.len() >= max_size) {
+            return Ok(());
+        }
+        let symbol = literals.decode(reader)? as usize;
+        if (symbol < 256) {
+            out.push(symbol as u8);
+        }
+        else if (symbol == 256) {
+            return Ok(());
+        }
+        else {
+            // Copy length bytes from distance back, which can overlap
+            let symbol = symbol - 257;
+            if (symbol >= LENGTH_BASE.len()) {
+                return Err("invalid length code".to_string());
+            }
+            let length = LENGTH_BASE[symbol] as usize + reader.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+            let symbol = distances.decode(reader)? as usize;
+            if (symbol >= DIST_BASE.len()) {
+                return Err("invalid distance code".to_string());
+            }
+            let distance = DIST_BASE[symbol] as usize + reader.bits(DIST_EXTRA[symbol] as u32)? as usize;
+            if (distance > out.len()) {
+                return Err("distance is further back than the start of the data".to_string());
+            }
+
+            let start = out.len() - distance;
+            for i in 0..length.min(max_size - out.len()) {
+                out.push(out[start + i]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(file: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+        file.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        file.extend_from_slice(chunk_type);
+        file.extend_from_slice(data);
+        file.extend_from_slice(&[0; 4]); // CRC isn't checked
+    }
+
+    // 8 bit RGB PNG with the scanlines stored uncompressed
+    fn rgb_png(width: u32, height: u32, scanlines: &[u8]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut zlib = vec![0x78, 0x01, 0x01];
+        zlib.extend_from_slice(&(scanlines.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(scanlines.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(scanlines);
+        zlib.extend_from_slice(&[0; 4]); // Adler-32 isn't checked
+
+        let mut file = SIGNATURE.to_vec();
+        chunk(&mut file, b"IHDR", &header);
+        chunk(&mut file, b"IDAT", &zlib);
+        chunk(&mut file, b"IEND", &[]);
+        file
+    }
+
+    #[test]
+    fn decodes_stored_rgb() {
+        let png = decode_png(&rgb_png(2, 1, &[0, 1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!((png.width, png.height), (2, 1));
+        assert_eq!(png.rgb, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let file = rgb_png(2, 2, &[0, 1, 2, 3, 4, 5, 6, 0, 1, 2, 3, 4, 5, 6]);
+        for length in [4, 8, 20, 33, file.len() - 12] {
+            assert!(decode_png(&file[..length]).is_err(), "decoded {} bytes", length);
+        }
+
+        // Fewer scanlines than the header says
+        let error = decode_png(&rgb_png(2, 2, &[0, 1, 2, 3, 4, 5, 6])).err().unwrap();
+        assert_eq!(error, "image data is too short");
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        for (width, height) in [(MAX_IMAGE_SIZE + 1, 1), (1, MAX_IMAGE_SIZE + 1), (u32::MAX, u32::MAX)] {
+            let error = decode_png(&rgb_png(width, height, &[0, 1, 2, 3])).err().unwrap();
+            assert!(error.contains("largest supported side"), "{}", error);
+        }
+
+        // A stream that inflates to more than the header allows stops at the image size
+        let png = decode_png(&rgb_png(1, 1, &[0, 1, 2, 3, 4, 5, 6, 7, 8])).unwrap();
+        assert_eq!(png.rgb, vec![1, 2, 3]);
+    }
+}
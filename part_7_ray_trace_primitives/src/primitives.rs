@@ -2,17 +2,35 @@
 #![allow(unused_parens)]
 #![allow(non_snake_case)]
 
+use std::any::Any;
+use std::f32::consts::PI;
+
+use crate::aabb;
+use crate::aabb::Aabb;
+use crate::hit::{hit_record, HitRecord};
+use crate::material::MaterialId;
 use crate::ray::Ray;
+use crate::sampling;
 use crate::transform::Transform;
 use crate::vec2::*;
 use crate::{vec2, vec3};
 use crate::vec3::*;
 
 pub trait Primitive {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool;
+    // Closest hit inside the ray's (t_min, t_max) interval
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord>;
+    // True if the ray crosses the surface anywhere inside its interval
     fn intersect_shadow(&self, ray: &Ray) -> bool;
-    fn get_color(&self) -> &Vec3;
+    fn get_material(&self) -> MaterialId;
     fn get_transform(&self) -> &Transform;
+    fn as_any(&self) -> &dyn Any; // For downcasting to the concrete type, e.g. when saving a scene
+    fn get_local_bounds(&self) -> Aabb;
+
+    // World space bounds of the local bounds transformed by get_transform().
+    // Infinite local bounds stay infinite.
+    fn get_bounds(&self) -> Aabb {
+        self.get_transform().local_to_world_bounds(self.get_local_bounds())
+    }
 }
 
 // Utility functions to make porting easier
@@ -24,6 +42,32 @@ fn max(a: f32, b:f32) -> f32 {
     a.max(b)
 }
 
+fn in_interval(t: f32, t_min: f32, t_max: f32) -> bool {
+    (t > t_min) && (t < t_max)
+}
+
+// Newton steps along the ray on the implicit surface f, with gradient grad.
+// The f32 quartic solvers can be off by about 1% of the object's size, which
+// is far more than the ray epsilon, so rays leaving the surface would hit it
+// again without this.
+fn refine_root(t: f32, ro: Vec3, rd: Vec3, f: impl Fn(Vec3) -> f32, grad: impl Fn(Vec3) -> Vec3) -> f32 {
+    let mut t = t;
+    for _ in 0..2 {
+        let pos = ro + t * rd;
+        let slope = vec3::dot(grad(pos), rd);
+        if (slope == 0.0) {
+            break;
+        }
+        t -= f(pos) / slope;
+    }
+    t
+}
+
+// Shadow test for primitives with nothing cheaper than finding the closest hit
+fn intersect_shadow_with_illum(prim: &dyn Primitive, ray: &Ray) -> bool {
+    prim.intersect_illum(ray).is_some()
+}
+
 fn abs(value: f32) -> f32 {
     value.abs()
 }
@@ -60,28 +104,91 @@ fn pow(value: f32, y: f32) -> f32 {
     value.powf(y)
 }
 
+// Some of the convex intersectors below only find where a ray enters the
+// shape. A ray starting inside leaves where the reversed ray, cast back from
+// just past the far side of the local bounds, enters. Returns the start of
+// the reversed ray and its distance along the original ray, or None if ro
+// isn't inside the bounds.
+fn reverse_from_bounds(bounds: &Aabb, ro: Vec3, rd: Vec3) -> Option<(Vec3, f32)> {
+    if (!bounds.contains(ro)) {
+        return None;
+    }
+    let far_t = bounds.exit_distance(ro, rd) * 1.001 + 1e-3;
+    Some((ro + far_t * rd, far_t))
+}
+
+// The get_uv() functions below work in local space and return the UV and the
+// local direction u increases in, which becomes the tangent.
+
+// Longitude and latitude of a unit direction, u going around Y and v from the
+// bottom up. dpdu is zero at the poles.
+fn spherical_uv(dir: Vec3) -> (Vec2, Vec3) {
+    let u = 0.5 + atan2(dir.x, dir.z) / (2.0 * PI);
+    let v = 0.5 + dir.y.clamp(-1.0, 1.0).asin() / PI;
+    (vec2(u, v), vec3(dir.z, 0.0, -dir.x))
+}
+
+// Signed unit axis along v's largest component
+fn dominant_axis(v: Vec3) -> Vec3 {
+    let a = vec3::abs(v);
+    if (a.x >= a.y) && (a.x >= a.z) {
+        vec3(sign(v.x), 0.0, 0.0)
+    }
+    else if (a.y >= a.z) {
+        vec3(0.0, sign(v.y), 0.0)
+    }
+    else {
+        vec3(0.0, 0.0, sign(v.z))
+    }
+}
+
+// Directions u and v increase in on the cube face facing along axis. Side
+// faces have v going up Y and the top and bottom have u along X, so every
+// face reads the right way round from outside.
+fn cube_face_axes(axis: Vec3) -> (Vec3, Vec3) {
+    let up = if (axis.y != 0.0) { vec3(0.0, 0.0, -axis.y) } else { vec3::Y_AXIS };
+    (vec3::cross(up, axis), up)
+}
+
+// Each face of a box with half size extent covers the whole UV square
+fn cube_face_uv(pos: Vec3, extent: Vec3) -> (Vec2, Vec3) {
+    let (dpdu, dpdv) = cube_face_axes(dominant_axis(pos / extent));
+    let u = 0.5 + 0.5 * vec3::dot(pos, dpdu) / vec3::dot(extent, vec3::abs(dpdu));
+    let v = 0.5 + 0.5 * vec3::dot(pos, dpdv) / vec3::dot(extent, vec3::abs(dpdv));
+    (vec2(u, v), dpdu)
+}
+
+// Hit record with UVs from a primitive's get_uv()
+fn hit_record_uv(ray: &Ray, t: f32, N: Vec3, material: MaterialId, transform: &Transform, (uv, dpdu): (Vec2, Vec3)) -> HitRecord {
+    let mut record = hit_record(ray, t, N, material);
+    record.set_uv(uv, transform.local_to_world_vector(dpdu));
+    record
+}
+
 // =====================================================================================================================
 // Sphere
 // =====================================================================================================================
 pub struct Sphere {
     pub transform : Transform,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl Sphere {
-    fn get_normal(&self, P: Vec3) -> Vec3 {
-        let pos = self.transform.world_to_local_point(P);
+    fn get_normal(&self, transform: &Transform, P: Vec3) -> Vec3 {
+        let pos = transform.world_to_local_point(P);
         let N = vec3::normalize(pos);
-        self.transform.local_to_world_vector(N)
+        transform.local_to_world_normal(N)
+    }
+
+    fn get_uv(&self, pos: Vec3) -> (Vec2, Vec3) {
+        spherical_uv(vec3::normalize(pos))
     }
 }
 
 impl Primitive for Sphere {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let radius = 1.0;
         let f  = local_ray.pos;
@@ -101,21 +208,21 @@ impl Primitive for Sphere {
             hit = true;
         }
 
-        let t = if (t1 < t2) { t1 } else { t2 };
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.get_normal(*out_P);
+        // Far root when the near one is outside the interval, e.g. starting inside
+        let t_near = if (t1 < t2) { t1 } else { t2 };
+        let t_far = if (t1 < t2) { t2 } else { t1 };
+        let t = if (ray.contains(t_near)) { t_near } else { t_far };
+        hit = hit && ray.contains(t);
+        if (!hit) {
+            return None;
         }
 
-        return hit;
+        Some(hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, self.get_uv(local_ray.at(t))))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let radius = 1.0;
         let f  = local_ray.pos;
@@ -125,26 +232,33 @@ impl Primitive for Sphere {
         let s  = f + (bi / a) * local_ray.dir;
         let discr = radius * radius - vec3::dot(s, s);
 
-        let mut t1: f32 = -1.0;
-        let mut t2: f32 = -1.0;
-        if (discr >= 0.0) {
-            let q = bi + bi.signum() * (a * discr).sqrt();
-            t1 = c / q;
-            t2 = q / a;
+        if (discr < 0.0) {
+            return false;
         }
 
-        let t = if (t1 < t2) { t1 } else { t2 };
-        let hit = if (t > 0.0) { true } else { false };
+        let q = bi + bi.signum() * (a * discr).sqrt();
+        let t1 = c / q;
+        let t2 = q / a;
+        let hit = ray.contains(t1) || ray.contains(t2);
         return hit;
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        let radius = 1.0;
+        aabb::aabb(vec3::from_scalar(-radius), vec3::from_scalar(radius))
+    }
 }
 
 // =====================================================================================================================
@@ -154,15 +268,13 @@ impl Primitive for Sphere {
 pub struct AABox {
     pub transform: Transform,
     pub size: Vec3,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl Primitive for AABox {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let ro = local_ray.pos;
         let rd = local_ray.dir;
@@ -175,27 +287,23 @@ impl Primitive for AABox {
         let tN = max(max( t1.x, t1.y ), t1.z);
         let tF = min(min( t2.x, t2.y ), t2.z);
 
-        // tN < tF must be true for valid intersection
-        // tF < 0 means the box is behind us
-        let hit = if (tN < tF) && (tF >= 0.0) { true } else { false };
-        if (hit) {
-            let outside = if (tN > 0.0) { true } else { false };
-            let t = if outside { tN } else { tF };
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            let mut N = if outside { vec3::step(vec3::from_scalar(tN), t1) } else { vec3::step(t2, vec3::from_scalar(tF)) };
-            N *= -vec3::sign(rd);
-            *out_N = self.transform.local_to_world_vector(N);
+        // tN < tF must be true for valid intersection, then the entry is used
+        // if it's inside the ray's interval, otherwise the exit
+        let outside = ray.contains(tN);
+        let hit = (tN < tF) && (outside || ray.contains(tF));
+        if (!hit) {
+            return None;
         }
 
-        return hit;
+        let t = if outside { tN } else { tF };
+        // Outward facing, so it points along the ray when leaving the box
+        let N = if outside { -vec3::sign(rd) * vec3::step(vec3::from_scalar(tN), t1) } else { vec3::sign(rd) * vec3::step(t2, vec3::from_scalar(tF)) };
+        Some(hit_record_uv(ray, t, transform.local_to_world_normal(N), self.material, &transform, cube_face_uv(local_ray.at(t), self.size)))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let ro = local_ray.pos;
         let rd = local_ray.dir;
@@ -208,19 +316,27 @@ impl Primitive for AABox {
         let tN = max(max( t1.x, t1.y ), t1.z);
         let tF = min(min( t2.x, t2.y ), t2.z);
 
-        // tN < tF must be true for valid intersection
-        // tF < 0 means the box is behind us
-        let hit = if (tN < tF) && (tF >= 0.0) { true } else { false };
+        // tN < tF must be true for valid intersection, then either side of
+        // the box has to be inside the interval
+        let hit = (tN < tF) && (ray.contains(tN) || ray.contains(tF));
         return hit;
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        aabb::aabb(-self.size, self.size)
+    }
 }
 
 // =====================================================================================================================
@@ -231,26 +347,27 @@ pub struct RoundedBox {
     pub transform: Transform,
     pub size: Vec3,
     pub radius: f32,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl RoundedBox {
-    fn get_normal(&self, P: Vec3) -> Vec3 {
-        let pos = self.transform.world_to_local_point(P);
+    fn get_normal(&self, transform: &Transform, P: Vec3) -> Vec3 {
+        let pos = transform.world_to_local_point(P);
         let N = vec3::sign(pos) * vec3::normalize(vec3::max(vec3::abs(pos) - self.size, vec3::from_scalar(0.0)));
-        self.transform.local_to_world_vector(N)
+        transform.local_to_world_normal(N)
     }
 }
 
-impl Primitive for crate::primitives::RoundedBox {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
-
-        let mut ro = local_ray.pos;
-        let mut rd = local_ray.dir;
+impl RoundedBox {
+    // Local space signed distance, negative inside
+    fn distance(&self, pos: Vec3) -> f32 {
+        let q = vec3::abs(pos) - self.size;
+        vec3::length(vec3::max(q, vec3::ZERO)) + min(max(max(q.x, q.y), q.z), 0.0) - self.radius
+    }
+
+    // Local space distance to where the ray enters the box. Only valid when
+    // starting outside, the edge tests can report false hits from inside.
+    fn intersect_entry(&self, mut ro: Vec3, mut rd: Vec3) -> Option<f32> {
         let size = self.size;
         let rad  = self.radius;
         let vrad = vec3::from_scalar(rad);
@@ -264,7 +381,7 @@ impl Primitive for crate::primitives::RoundedBox {
         let tN = max( max( t1.x, t1.y ), t1.z );
         let tF = min( min( t2.x, t2.y ), t2.z );
         if (tN > tF) || (tF < 0.0) {
-            return false;
+            return None;
         }
 
         let mut t = tN;
@@ -281,12 +398,7 @@ impl Primitive for crate::primitives::RoundedBox {
         pos = vec3::max(pos.xyz(), pos.yzx());
         if (min(min(pos.x, pos.y), pos.z) < 0.0) {
             let hit = if (t >= 0.0) { true } else { false };
-            if (hit) {
-                *out_t = t;
-                *out_P = ray.pos + t * ray.dir;
-                *out_N = self.get_normal(*out_P);
-            }
-            return hit;
+            return if hit { Some(t) } else { None };
         }
 
         // some precomputation
@@ -349,30 +461,54 @@ impl Primitive for crate::primitives::RoundedBox {
         }
 
         let hit = if (t > 0.0) && (t < 1e19) { true } else { false };
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.get_normal(*out_P);
+        return if hit { Some(t) } else { None };
+    }
+}
+
+impl Primitive for crate::primitives::RoundedBox {
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
+
+        // Start at t_min, the tests below find the first hit after the origin
+        let ro = local_ray.at(local_ray.t_min);
+        let rd = local_ray.dir;
+
+        let mut t = None;
+        if (self.distance(ro) >= 0.0) {
+            t = self.intersect_entry(ro, rd);
+        }
+        else if let Some((far_pos, far_t)) = reverse_from_bounds(&self.get_local_bounds(), ro, rd) {
+            // Exit hit when starting inside
+            t = self.intersect_entry(far_pos, -rd).map(|t| far_t - t).filter(|t| *t > 0.0);
         }
 
-        return hit;
+        t.map(|t| local_ray.t_min + t)
+            .filter(|t| local_ray.contains(*t))
+            .map(|t| hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, cube_face_uv(local_ray.at(t), self.size + vec3::from_scalar(self.radius))))
     }
 
+
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let mut t = 0.0;
-        let mut P = vec3::ZERO;
-        let mut N = vec3::ZERO;
-        let hit = self.intersect_illum(ray, &mut t, &mut P, &mut N);
-        return hit && (t > 0.0);
+        intersect_shadow_with_illum(self, ray)
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        let extent = self.size + vec3::from_scalar(self.radius);
+        aabb::aabb(-extent, extent)
+    }
 }
 
 // =====================================================================================================================
@@ -380,49 +516,62 @@ impl Primitive for crate::primitives::RoundedBox {
 // =====================================================================================================================
 pub struct Plane {
     pub transform : Transform,
-    pub color: Vec3,
+    pub material: MaterialId,
+}
+
+impl Plane {
+    // One UV unit per world unit whatever the plane's scale, so textures
+    // tile across it at a fixed size. v runs along -Z to keep u, v and the
+    // normal right handed.
+    fn get_uv(&self, transform: &Transform, pos: Vec3) -> (Vec2, Vec3) {
+        let scale_u = vec3::length(transform.local_to_world_vector(vec3::X_AXIS));
+        let scale_v = vec3::length(transform.local_to_world_vector(vec3::Z_AXIS));
+        (vec2(pos.x * scale_u, -pos.z * scale_v), vec3::X_AXIS)
+    }
 }
 
 impl Primitive for Plane {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let plane_dir = vec3(0.0, 1.0, 0.0);
         let t = -vec3::dot(local_ray.pos, plane_dir) / vec3::dot(local_ray.dir, plane_dir);
 
-        let hit = if (t > 0.0) { true } else { false };
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.transform.local_to_world_vector(plane_dir);
+        let hit = ray.contains(t);
+        if (!hit) {
+            return None;
         }
 
-        return hit;
+        Some(hit_record_uv(ray, t, transform.local_to_world_normal(plane_dir), self.material, &transform, self.get_uv(&transform, local_ray.at(t))))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let plane_dir = vec3(0.0, 1.0, 0.0);
         let t = -vec3::dot(local_ray.pos, plane_dir) / vec3::dot(local_ray.dir, plane_dir);
 
-        let hit = if (t > 0.0) { true } else { false };
+        let hit = ray.contains(t);
         return hit;
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        Aabb::infinite()
+    }
 }
 
 // =====================================================================================================================
@@ -434,18 +583,12 @@ pub struct Cylinder {
     pub start: Vec3,
     pub end: Vec3,
     pub radius: f32,
-    pub color : Vec3,
+    pub material : MaterialId,
 }
 
-impl Primitive for Cylinder {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
-
-        let ro = local_ray.pos;
-        let rd = local_ray.dir;
+impl Cylinder {
+    // Local space distance and normal where the ray enters the cylinder
+    fn intersect_entry(&self, ro: Vec3, rd: Vec3) -> Option<(f32, Vec3)> {
         let ra = self.radius;
 
         let pa = self.start;
@@ -464,7 +607,7 @@ impl Primitive for Cylinder {
         let mut h = b*b - a*c;
 
         if (h < 0.0) {
-            return false;
+            return None;
         }
 
         h = sqrt(h);
@@ -488,30 +631,79 @@ impl Primitive for Cylinder {
             }
         }
 
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.transform.local_to_world_vector(N);
+        return if hit && (t > 0.0) { Some((t, N)) } else { None };
+    }
+
+    // u goes around the axis and v along it from start to end. The caps are
+    // mapped flat, with the UV square spanning the cap's diameter.
+    fn get_uv(&self, pos: Vec3, N: Vec3) -> (Vec2, Vec3) {
+        let ca = self.end - self.start;
+        let axis = vec3::normalize(ca);
+        let (T, B) = sampling::make_basis(axis);
+        let oc = pos - self.start;
+        let radial = oc - vec3::dot(oc, axis) * axis;
+
+        let cap = vec3::dot(N, axis);
+        if (abs(cap) > 0.5) {
+            // Flip u on the start cap so it isn't mirrored seen from outside
+            let dpdu = sign(cap) * T;
+            let u = 0.5 + 0.5 * vec3::dot(radial, dpdu) / self.radius;
+            let v = 0.5 + 0.5 * vec3::dot(radial, B) / self.radius;
+            return (vec2(u, v), dpdu);
         }
 
-        return hit && (t > 0.0);
+        let u = 0.5 + atan2(vec3::dot(radial, B), vec3::dot(radial, T)) / (2.0 * PI);
+        let v = vec3::dot(oc, ca) / vec3::dot(ca, ca);
+        (vec2(u, v), vec3::cross(axis, radial))
+    }
+}
+
+impl Primitive for Cylinder {
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
+
+        // Start at t_min, the tests below find the first hit after the origin
+        let ro = local_ray.at(local_ray.t_min);
+        let rd = local_ray.dir;
+
+        let mut hit = self.intersect_entry(ro, rd);
+        if (hit.is_none()) {
+            // Exit hit when starting inside
+            if let Some((far_pos, far_t)) = reverse_from_bounds(&self.get_local_bounds(), ro, rd) {
+                hit = self.intersect_entry(far_pos, -rd).map(|(t, N)| (far_t - t, N)).filter(|(t, _)| *t > 0.0);
+            }
+        }
+
+        hit.map(|(t, N)| (local_ray.t_min + t, N))
+            .filter(|(t, _)| local_ray.contains(*t))
+            .map(|(t, N)| hit_record_uv(ray, t, transform.local_to_world_normal(N), self.material, &transform, self.get_uv(local_ray.at(t), N)))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let mut t = 0.0;
-        let mut P = vec3::ZERO;
-        let mut N = vec3::ZERO;
-        let hit = self.intersect_illum(ray, &mut t, &mut P, &mut N);
-        return hit && (t > 0.0);
+        intersect_shadow_with_illum(self, ray)
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // https://iquilezles.org/articles/diskbbox/
+    fn get_local_bounds(&self) -> Aabb {
+        let pa = self.start;
+        let pb = self.end;
+        let a = pb - pa;
+        let e = self.radius * vec3::sqrt(vec3::max(1.0 - a*a/vec3::dot(a, a), vec3::ZERO));
+        aabb::aabb(vec3::min(pa, pb) - e, vec3::max(pa, pb) + e)
+    }
 }
 
 // =====================================================================================================================
@@ -521,23 +713,27 @@ impl Primitive for Cylinder {
 pub struct Ellipsoid {
     pub transform : Transform,
     pub radii: Vec3,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl Ellipsoid {
-    fn get_normal(&self, P: Vec3) -> Vec3 {
-        let pos = self.transform.world_to_local_point(P);
+    fn get_normal(&self, transform: &Transform, P: Vec3) -> Vec3 {
+        let pos = transform.world_to_local_point(P);
         let N = vec3::normalize( pos / (self.radii * self.radii) );
-        self.transform.local_to_world_vector(N)
+        transform.local_to_world_normal(N)
+    }
+
+    // Spherical UVs of the point on the unit sphere that was stretched to pos
+    fn get_uv(&self, pos: Vec3) -> (Vec2, Vec3) {
+        let (uv, dpdu) = spherical_uv(vec3::normalize(pos / self.radii));
+        (uv, dpdu * self.radii)
     }
 }
 
 impl Primitive for Ellipsoid {
-    fn intersect_illum(&self, ray: &Ray, out_t : &mut f32, out_P: &mut Vec3, out_N: &mut Vec3)  -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let ocn = local_ray.pos / self.radii;
         let rdn = local_ray.dir / self.radii;
@@ -547,25 +743,25 @@ impl Primitive for Ellipsoid {
         let h = b*b - a*(c-1.0);
         if( h < 0.0 ) {
             // no intersection
-            return false;
+            return None;
         }
 
-        let t = (-b - h.sqrt()) / a;
-        let hit = if (t > 0.0) { true } else { false };
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.get_normal(*out_P);
+        // Far root when the near one is outside the interval, e.g. starting inside
+        let mut t = (-b - h.sqrt()) / a;
+        if (!ray.contains(t)) {
+            t = (-b + h.sqrt()) / a;
+        }
+        let hit = ray.contains(t);
+        if (!hit) {
+            return None;
         }
 
-        return hit;
+        Some(hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, self.get_uv(local_ray.at(t))))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
         let ocn = local_ray.pos / self.radii;
         let rdn = local_ray.dir / self.radii;
@@ -578,18 +774,27 @@ impl Primitive for Ellipsoid {
             return false;
         }
 
-        let t = (-b - h.sqrt()) / a;
-        let hit = if (t > 0.0) { true } else { false };
+        let t1 = (-b - h.sqrt()) / a;
+        let t2 = (-b + h.sqrt()) / a;
+        let hit = ray.contains(t1) || ray.contains(t2);
         return hit;
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn get_local_bounds(&self) -> Aabb {
+        aabb::aabb(-self.radii, self.radii)
+    }
 }
 
 // =====================================================================================================================
@@ -600,30 +805,62 @@ pub struct Torus {
     pub transform : Transform,
     pub major_radius : f32,
     pub minor_radius : f32,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl Torus {
-    fn get_normal(&self, P : Vec3) -> Vec3 {
-        let pos = self.transform.world_to_local_point(P);
+    fn get_normal(&self, transform: &Transform, P: Vec3) -> Vec3 {
+        let pos = transform.world_to_local_point(P);
         let Ra = self.major_radius;
         let ra = self.minor_radius;
         let Ra2 = Ra * Ra;
         let ra2 = ra * ra;
         let N = vec3::normalize(pos*(vec3::dot(pos, pos) - ra2 - Ra2*vec3(1.0, 1.0, -1.0)));
-        self.transform.local_to_world_vector(N)
+        transform.local_to_world_normal(N)
+    }
+
+    // Local space implicit surface, zero on the torus
+    fn implicit(&self, pos: Vec3) -> f32 {
+        let Ra2 = self.major_radius * self.major_radius;
+        let ra2 = self.minor_radius * self.minor_radius;
+        let k = vec3::dot(pos, pos) + Ra2 - ra2;
+        k*k - 4.0*Ra2*(pos.x*pos.x + pos.y*pos.y)
+    }
+
+    fn implicit_gradient(&self, pos: Vec3) -> Vec3 {
+        let Ra2 = self.major_radius * self.major_radius;
+        let ra2 = self.minor_radius * self.minor_radius;
+        4.0*pos*(vec3::dot(pos, pos) - ra2 - Ra2*vec3(1.0, 1.0, -1.0))
+    }
+
+    // u is the angle around the Z axis and v the angle around the tube,
+    // starting on the outer edge
+    fn get_uv(&self, pos: Vec3) -> (Vec2, Vec3) {
+        let radial = vec3::normalize(vec3(pos.x, pos.y, 0.0));
+        let tube = pos - self.major_radius * radial;
+        let u = 0.5 + atan2(pos.y, pos.x) / (2.0 * PI);
+        let v = 0.5 + atan2(tube.z, vec3::dot(tube, radial)) / (2.0 * PI);
+        (vec2(u, v), vec3(-pos.y, pos.x, 0.0))
     }
 }
 
 impl Primitive for Torus {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3)  -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: vec3::normalize(self.transform.world_to_local_vector(ray.dir)),
-        };
-
-        let ro = local_ray.pos;
-        let rd = local_ray.dir;
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        // The solver needs a unit direction, so t is in local units until the
+        // end and the interval is scaled to match
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
+        let dir_length = vec3::length(local_ray.dir);
+        let t_min = ray.t_min * dir_length;
+        let t_max = ray.t_max * dir_length;
+
+        // Like Goursat, starting near the surface loses precision so those
+        // rays are solved from further back
+        let bounds = self.get_local_bounds();
+        let back = if (bounds.contains(local_ray.pos)) { 2.0 * vec3::length(bounds.extent()) } else { 0.0 };
+
+        let rd = local_ray.dir / dir_length;
+        let ro = local_ray.pos - back * rd;
 
         let mut po = 1.0;
 
@@ -640,7 +877,7 @@ impl Primitive for Torus {
         {
             let h = n*n - m + (Ra + ra)*(Ra + ra);
             if (h < 0.0) {
-                return false;
+                return None;
             }
             //let t = -n-sqrt(h); // could use this to compute intersections from ro+t*rd
         }
@@ -696,14 +933,14 @@ impl Primitive for Torus {
         let mut d2 = z*z - 3.0*c0;
         if (abs(d1) < 1.0e-4) {
             if (d2 < 0.0) {
-                return false;
+                return None;
             }
             d2 = sqrt(d2);
         }
         else
         {
             if (d1 < 0.0) {
-                return false;
+                return None;
             }
             d1 = sqrt(d1/2.0);
             d2 = c1/d1;
@@ -711,7 +948,8 @@ impl Primitive for Torus {
 
         //----------------------------------
 
-        let mut t = 1e20;
+        let refine = |t| refine_root(t, ro, rd, |pos| self.implicit(pos), |pos| self.implicit_gradient(pos)) - back;
+        let mut t = t_max;
 
         h = d1*d1 - z + d2;
         if (h > 0.0) {
@@ -719,16 +957,18 @@ impl Primitive for Torus {
             let mut t1 = -d1 - h - k3;
             //t1 = (po<0.0)?2.0/t1:t1;
             t1 = if (po < 0.0) { 2.0/t1 } else { t1 };
+            t1 = refine(t1);
 
             let mut t2 = -d1 + h - k3;
             //t2 = (po<0.0)?2.0/t2:t2;
             t2 = if (po < 0.0) { 2.0/t2 } else { t2 };
+            t2 = refine(t2);
 
-            if (t1 > 0.0) {
-                t = t1;
+            if (in_interval(t1, t_min, t_max)) {
+                t = min(t, t1);
             }
 
-            if (t2 > 0.0) {
+            if (in_interval(t2, t_min, t_max)) {
                 t = min(t, t2);
             }
         }
@@ -739,45 +979,53 @@ impl Primitive for Torus {
             let mut t1 = d1 - h - k3;
             //t1 = (po<0.0)?2.0/t1:t1;
             t1 = if (po < 0.0) { 2.0/t1 } else { t1 };
+            t1 = refine(t1);
 
             let mut t2 = d1 + h - k3;
             //t2 = (po<0.0)?2.0/t2:t2;
             t2 = if (po < 0.0) { 2.0/t2 } else { t2 };
+            t2 = refine(t2);
 
-            if (t1 > 0.0) {
+            if (in_interval(t1, t_min, t_max)) {
                 t = min(t, t1);
             }
 
-            if (t2 > 0.0) {
+            if (in_interval(t2, t_min, t_max)) {
                 t = min(t, t2);
             }
         }
 
-        let hit = if (t > 0.0) && (t < 1e20) { true } else { false };
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.get_normal(*out_P);
+        let hit = t < t_max;
+        if (!hit) {
+            return None;
         }
 
-        return hit
+        let t = t / dir_length;
+        Some(hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, self.get_uv(local_ray.at(t))))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let mut t = 0.0;
-        let mut P = vec3::ZERO;
-        let mut N = vec3::ZERO;
-        let hit = self.intersect_illum(ray, &mut t, &mut P, &mut N);
-        return hit;
+        intersect_shadow_with_illum(self, ray)
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // The torus is centered on the origin and revolves around the Z axis
+    fn get_local_bounds(&self) -> Aabb {
+        let r = self.major_radius + self.minor_radius;
+        let extent = vec3(r, r, self.minor_radius);
+        aabb::aabb(-extent, extent)
+    }
 }
 
 // =====================================================================================================================
@@ -788,25 +1036,47 @@ pub struct Goursat {
     pub transform : Transform,
     pub ka : f32,
     pub kb : f32,
-    pub color: Vec3,
+    pub material: MaterialId,
 }
 
 impl Goursat {
-    fn get_normal(&self, P : Vec3) -> Vec3 {
-        let pos = self.transform.world_to_local_point(P);
+    fn get_normal(&self, transform: &Transform, P: Vec3) -> Vec3 {
+        let pos = transform.world_to_local_point(P);
         let N = vec3::normalize((4.0*pos*pos*pos) - (2.0*pos*self.kb*self.kb));
-        self.transform.local_to_world_vector(N)
+        transform.local_to_world_normal(N)
+    }
+
+    // Local space implicit surface, zero on the surface
+    fn implicit(&self, pos: Vec3) -> f32 {
+        let pos2 = pos*pos;
+        vec3::dot(pos2, pos2) - self.kb*vec3::dot(pos, pos) + self.ka
+    }
+
+    fn implicit_gradient(&self, pos: Vec3) -> Vec3 {
+        (4.0*pos*pos*pos) - (2.0*self.kb*pos)
+    }
+
+    // There's no natural parameterization, so the position is projected
+    // along whichever axis the normal is closest to, using the same axes as
+    // the faces of a box. UVs are in local units and tile.
+    fn get_uv(&self, pos: Vec3) -> (Vec2, Vec3) {
+        let (dpdu, dpdv) = cube_face_axes(dominant_axis(self.implicit_gradient(pos)));
+        (vec2(vec3::dot(pos, dpdu), vec3::dot(pos, dpdv)), dpdu)
     }
 }
 
 impl Primitive for Goursat {
-    fn intersect_illum(&self, ray: &Ray, out_t: &mut f32, out_P: &mut Vec3, out_N: &mut Vec3)  -> bool {
-        let local_ray = Ray {
-            pos: self.transform.world_to_local_point(ray.pos),
-            dir: self.transform.world_to_local_vector(ray.dir),
-        };
+    fn intersect_illum(&self, ray: &Ray) -> Option<HitRecord> {
+        let transform = self.transform.at_time(ray.time);
+        let local_ray = transform.world_to_local_ray(ray);
 
-        let ro = local_ray.pos;
+        // The solver loses precision when starting on or near the surface,
+        // which is where secondary rays start. Those are solved from a point
+        // pushed back outside the bounds and the roots shifted back after.
+        let bounds = self.get_local_bounds();
+        let back = if (bounds.contains(local_ray.pos)) { 2.0 * vec3::length(bounds.extent()) / vec3::length(local_ray.dir) } else { 0.0 };
+
+        let ro = local_ray.at(-back);
         let rd = local_ray.dir;
         let ka = self.ka;
         let kb = self.kb;
@@ -817,6 +1087,8 @@ impl Primitive for Goursat {
         let ro2 = ro*ro;
         let ro3 = ro2*ro;
 
+        let refine = |t| refine_root(t, ro, rd, |pos| self.implicit(pos), |pos| self.implicit_gradient(pos)) - back;
+
         // raw quartic
         let k4 = vec3::dot(rd2, rd2);
         let mut k3 = vec3::dot(ro, rd3);
@@ -866,20 +1138,27 @@ impl Primitive for Goursat {
             let ks = x*x + y*y*3.0;
             let k = sqrt(ks);
 
-            let mut t = -0.5*po*abs(y)*sqrt(6.0/(k + x)) - 2.0*c1*(k + x)/(ks +x*k) - k3;
+            // Both real roots, the second one is the exit when starting inside
+            let offset = 0.5*abs(y)*sqrt(6.0/(k + x));
+            let mut t1 = -po*offset - 2.0*c1*(k + x)/(ks +x*k) - k3;
+            let mut t2 =  po*offset - 2.0*c1*(k + x)/(ks +x*k) - k3;
             if (po <= 0.0) {
-                t = 1.0/t
+                t1 = 1.0/t1;
+                t2 = 1.0/t2;
             }
+            t1 = refine(t1);
+            t2 = refine(t2);
 
-            let hit =  if (t > 0.0) { true } else { false };
+            let mut t = ray.t_max;
+            if (ray.contains(t1)) { t = min(t, t1) };
+            if (ray.contains(t2)) { t = min(t, t2) };
 
-            if (hit) {
-                *out_t = t;
-                *out_P = ray.pos + t * ray.dir;
-                *out_N = self.get_normal(*out_P);
+            let hit = t < ray.t_max;
+            if (!hit) {
+                return None;
             }
 
-            return hit;
+            return Some(hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, self.get_uv(local_ray.at(t))));
         }
 
         // 4 intersections
@@ -890,7 +1169,7 @@ impl Primitive for Goursat {
 
         // no intersection
         if (d2 < 0.0) {
-            return false;
+            return None;
         }
 
         let d1 = sqrt(d2);
@@ -909,36 +1188,52 @@ impl Primitive for Goursat {
         let mut t4 = d1 + h2 - k3;
         if (po < 0.0) { t4 = 1.0 / t4; }
 
-        let mut t = 1e20;
-        if (t1 > 0.0) { t = t1 };
-        if (t2 > 0.0) { t = min(t, t2) };
-        if (t3 > 0.0) { t = min(t, t3) };
-        if (t4 > 0.0) { t = min(t, t4) };
+        let t1 = refine(t1);
+        let t2 = refine(t2);
+        let t3 = refine(t3);
+        let t4 = refine(t4);
+
+        let mut t = ray.t_max;
+        if (ray.contains(t1)) { t = min(t, t1) };
+        if (ray.contains(t2)) { t = min(t, t2) };
+        if (ray.contains(t3)) { t = min(t, t3) };
+        if (ray.contains(t4)) { t = min(t, t4) };
 
-        let hit = if ((t > 0.0) && (t < 1e20)) { true } else { false };
+        let hit = t < ray.t_max;
 
-        if (hit) {
-            *out_t = t;
-            *out_P = ray.pos + t * ray.dir;
-            *out_N = self.get_normal(*out_P);
+        if (!hit) {
+            return None;
         }
 
-        return hit;
+        Some(hit_record_uv(ray, t, self.get_normal(&transform, ray.at(t)), self.material, &transform, self.get_uv(local_ray.at(t))))
     }
 
     fn intersect_shadow(&self, ray: &Ray) -> bool {
-        let mut t = 0.0;
-        let mut P = vec3::ZERO;
-        let mut N = vec3::ZERO;
-        let hit = self.intersect_illum(ray, &mut t, &mut P, &mut N);
-        return hit;
+        intersect_shadow_with_illum(self, ray)
     }
 
-    fn get_color(&self) -> &Vec3 {
-        &self.color
+    fn get_material(&self) -> MaterialId {
+        self.material
     }
 
     fn get_transform(&self) -> &Transform {
         &self.transform
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // The surface is x^4 + y^4 + z^4 - kb*(x^2 + y^2 + z^2) + ka = 0. Each
+    // coordinate reaches its furthest point when the other two sit at the
+    // minimum of t^4 - kb*t^2 (which is -kb^2/4), leaving a quadratic in x^2.
+    // Padded slightly since the quartic solver's roots land a little past the
+    // exact surface.
+    fn get_local_bounds(&self) -> Aabb {
+        let ka = self.ka;
+        let kb = self.kb;
+        let x2 = 0.5 * (kb + (3.0*kb*kb - 4.0*ka).max(0.0).sqrt());
+        let extent = vec3::from_scalar(1.01 * x2.max(0.0).sqrt());
+        aabb::aabb(-extent, extent)
+    }
 }
@@ -3,10 +3,12 @@
 
 use crate::mat4;
 use crate::mat4::Mat4;
+use crate::vec3;
 use crate::vec3::*;
 
 static EPSILON: f32 = 1.19209e-07;
 
+#[derive(Debug, Copy, Clone)]
 pub struct Quat {
     w: f32,
     x: f32,
@@ -40,7 +42,7 @@ pub fn axis_angle(angle: f32, v: Vec3) -> Quat {
 
 // GLM's quat rotation
 pub fn rotation(orig: Vec3, dest: Vec3) -> Quat {
-    let cosTheta = dot(orig, dest);
+    let cosTheta = vec3::dot(orig, dest);
 
     if (cosTheta >= (1.0 - EPSILON)) {
         // orig and dest point in the same direction
@@ -106,3 +108,106 @@ pub fn to_mat4(q: Quat) -> Mat4 {
 
     Result
 }
+
+
+// GLM's quat_cast, from the rotation in the upper 3x3 of m
+pub fn from_mat4(m: Mat4) -> Quat {
+    let fourXSquaredMinus1 = m[0][0] - m[1][1] - m[2][2];
+    let fourYSquaredMinus1 = m[1][1] - m[0][0] - m[2][2];
+    let fourZSquaredMinus1 = m[2][2] - m[0][0] - m[1][1];
+    let fourWSquaredMinus1 = m[0][0] + m[1][1] + m[2][2];
+
+    let mut biggestIndex = 0;
+    let mut fourBiggestSquaredMinus1 = fourWSquaredMinus1;
+    if (fourXSquaredMinus1 > fourBiggestSquaredMinus1) {
+        fourBiggestSquaredMinus1 = fourXSquaredMinus1;
+        biggestIndex = 1;
+    }
+    if (fourYSquaredMinus1 > fourBiggestSquaredMinus1) {
+        fourBiggestSquaredMinus1 = fourYSquaredMinus1;
+        biggestIndex = 2;
+    }
+    if (fourZSquaredMinus1 > fourBiggestSquaredMinus1) {
+        fourBiggestSquaredMinus1 = fourZSquaredMinus1;
+        biggestIndex = 3;
+    }
+
+    let biggestVal = (fourBiggestSquaredMinus1 + 1.0).sqrt() * 0.5;
+    let mult = 0.25 / biggestVal;
+
+    match biggestIndex {
+        0 => quat(biggestVal, (m[1][2] - m[2][1]) * mult, (m[2][0] - m[0][2]) * mult, (m[0][1] - m[1][0]) * mult),
+        1 => quat((m[1][2] - m[2][1]) * mult, biggestVal, (m[0][1] + m[1][0]) * mult, (m[2][0] + m[0][2]) * mult),
+        2 => quat((m[2][0] - m[0][2]) * mult, (m[0][1] + m[1][0]) * mult, biggestVal, (m[1][2] + m[2][1]) * mult),
+        _ => quat((m[0][1] - m[1][0]) * mult, (m[2][0] + m[0][2]) * mult, (m[1][2] + m[2][1]) * mult, biggestVal),
+    }
+}
+
+pub fn dot(a: Quat, b: Quat) -> f32 {
+    (a.w * b.w) + (a.x * b.x) + (a.y * b.y) + (a.z * b.z)
+}
+
+// GLM's slerp, along the shortest path. Nearly equal rotations are lerped
+// and normalized since sin(angle) goes to zero.
+pub fn slerp(x: Quat, y: Quat, a: f32) -> Quat {
+    let mut z = y;
+    let mut cosTheta = dot(x, y);
+
+    // If cosTheta < 0, the interpolation will take the long way around the sphere.
+    // To fix this, one quat must be negated.
+    if (cosTheta < 0.0) {
+        z = quat(-y.w, -y.x, -y.y, -y.z);
+        cosTheta = -cosTheta;
+    }
+
+    let (wx, wz) = if (cosTheta > 1.0 - EPSILON) {
+        (1.0 - a, a)
+    }
+    else {
+        let angle = cosTheta.acos();
+        let sinAngle = angle.sin();
+        (((1.0 - a) * angle).sin() / sinAngle, (a * angle).sin() / sinAngle)
+    };
+
+    let q = quat((wx * x.w) + (wz * z.w), (wx * x.x) + (wz * z.x), (wx * x.y) + (wz * z.y), (wx * x.z) + (wz * z.z));
+    let len = dot(q, q).sqrt();
+    quat(q.w / len, q.x / len, q.y / len, q.z / len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat4::RotationOrder;
+
+    fn assert_same_rotation(a: Quat, b: Quat) {
+        // q and -q are the same rotation
+        assert!(dot(a, b).abs() > 1.0 - 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn mat4_round_trip() {
+        let angles = [vec3(0.0, 0.0, 0.0), vec3(0.3, -1.2, 2.5), vec3(3.0, 0.1, -0.4), vec3(-2.8, 1.5, 0.0)];
+        for &euler_angles in angles.iter() {
+            let m = mat4::rotate(euler_angles, RotationOrder::XYZ);
+            let round_trip = to_mat4(from_mat4(m));
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert!((round_trip[i][j] - m[i][j]).abs() < 1e-5, "{:?}: [{}][{}] {} != {}", euler_angles, i, j, round_trip[i][j], m[i][j]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn slerp_endpoints() {
+        let a = from_mat4(mat4::rotate(vec3(0.2, 0.4, -0.1), RotationOrder::XYZ));
+        let b = from_mat4(mat4::rotate(vec3(-1.0, 2.0, 0.5), RotationOrder::XYZ));
+        assert_same_rotation(slerp(a, b, 0.0), a);
+        assert_same_rotation(slerp(a, b, 1.0), b);
+
+        // Nearly equal rotations take the lerp path
+        let c = from_mat4(mat4::rotate(vec3(0.2, 0.4, -0.1 + 1e-4), RotationOrder::XYZ));
+        assert_same_rotation(slerp(a, c, 0.0), a);
+        assert_same_rotation(slerp(a, c, 1.0), c);
+    }
+}
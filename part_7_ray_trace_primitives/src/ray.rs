@@ -1,9 +1,60 @@
 #![allow(dead_code)]
+#![allow(non_snake_case)]
 
+use crate::vec3;
 use crate::vec3::Vec3;
 
+// Only hits strictly between t_min and t_max count. Closest hit searches
+// shrink t_max as they find hits and shadow rays set it to the distance to
+// the light. time is when the ray was cast during the shutter interval, and
+// moving primitives are intersected where they are at that time.
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub pos: Vec3,
     pub dir: Vec3,
+    pub t_min: f32,
+    pub t_max: f32,
+    pub time: f32,
+}
+
+// Smallest t_min relative to the size of the hit point's coordinates. Float
+// error in a hit point grows with its distance from the origin, so a fixed
+// offset is either too small far away or too large for small objects.
+const RELATIVE_EPSILON: f32 = 1e-4;
+const MIN_EPSILON: f32 = 1e-6;
+
+pub fn ray(pos: Vec3, dir: Vec3) -> Ray {
+    Ray { pos, dir, t_min: 0.0, t_max: f32::INFINITY, time: 0.0 }
+}
+
+impl Ray {
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.pos + t * self.dir
+    }
+
+    pub fn contains(&self, t: f32) -> bool {
+        (t > self.t_min) && (t < self.t_max)
+    }
+}
+
+pub fn surface_epsilon(P: Vec3) -> f32 {
+    let v = vec3::abs(P);
+    (RELATIVE_EPSILON * v.x.max(v.y).max(v.z)).max(MIN_EPSILON)
+}
+
+// Ray leaving a surface at P. It starts on the surface and skips the first
+// surface_epsilon(P) of its length so it doesn't hit the surface it left.
+// time is that of the ray that found the surface, so everything a camera
+// ray leads to sees the scene at the same moment.
+pub fn spawn_ray(P: Vec3, dir: Vec3, time: f32) -> Ray {
+    Ray { pos: P, dir, t_min: surface_epsilon(P), t_max: f32::INFINITY, time }
+}
+
+// Ray from a surface at P towards a point distance away along dir, stopping
+// short of it the same way it starts, so a light sitting on a surface isn't
+// blocked by that surface
+pub fn spawn_segment(P: Vec3, dir: Vec3, distance: f32, time: f32) -> Ray {
+    let t_min = surface_epsilon(P);
+    let t_max = if (distance.is_finite()) { distance - surface_epsilon(P + distance * dir) } else { f32::INFINITY };
+    Ray { pos: P, dir, t_min, t_max, time }
 }
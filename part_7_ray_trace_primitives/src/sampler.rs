@@ -0,0 +1,382 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+use crate::sampling;
+
+// Sample streams for the renderer. Each sample of a pixel starts a new
+// stream with start_pixel_sample(), then every get_1d() or get_2d() hands out
+// the next dimension. The first dimensions go to the film position, later
+// ones to lights and BRDFs in the order the integrator asks for them.
+// Everything is a function of the seed, the pixel, the sample index and the
+// dimension, so images don't depend on which thread traced what.
+// https://pbr-book.org/3ed-2018/Sampling_and_Reconstruction/Sampling_Interface
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SamplerType {
+    Independent, // Uniform random numbers from Pcg32
+    Stratified,  // Each sample of a pixel in its own jittered stratum of every dimension
+    Halton,      // Halton sequence with random digit permutations
+    Sobol,       // Owen scrambled Sobol (0, 2) sequence, shuffled per dimension
+    BlueNoise,   // R1 and R2 sequences shifted per pixel by a blue noise mask, so the error is blue noise
+}
+
+pub const SAMPLER_TYPES: [SamplerType; 5] = [
+    SamplerType::Independent,
+    SamplerType::Stratified,
+    SamplerType::Halton,
+    SamplerType::Sobol,
+    SamplerType::BlueNoise,
+];
+
+// Name used in scene files
+pub fn get_sampler_name(sampler_type: SamplerType) -> &'static str {
+    match sampler_type {
+        SamplerType::Independent => "independent",
+        SamplerType::Stratified => "stratified",
+        SamplerType::Halton => "halton",
+        SamplerType::Sobol => "sobol",
+        SamplerType::BlueNoise => "blue_noise",
+    }
+}
+
+// Halton dimensions past this many primes fall back to independent samples
+const HALTON_PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// R1 and R2 sequence generators, from the golden ratio and the plastic number
+// http://extremelearning.com.au/unreasonable-effectiveness-of-quasirandom-sequences/
+const R1_ALPHA: f64 = 0.618_033_988_749_894_9;
+const R2_ALPHA: (f64, f64) = (0.754_877_666_246_692_8, 0.569_840_290_998_053_3);
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON;
+
+// Blue noise mask tile width and height
+const BLUE_NOISE_SIZE: usize = 64;
+
+pub struct Sampler {
+    pub sampler_type  : SamplerType,
+    samples_per_pixel : u32,
+    seed              : u64,
+    rng               : Pcg32, // Independent samples and stratified jitter
+    pixel             : (u32, u32),
+    pixel_hash        : u64, // Seeds the scrambles of the current pixel
+    index             : u32, // Sample of the current pixel
+    dimension         : u32, // Next dimension to hand out
+}
+
+impl Sampler {
+    pub fn new(sampler_type: SamplerType, samples_per_pixel: u32, seed: u64) -> Sampler {
+        Sampler {
+            sampler_type,
+            samples_per_pixel : samples_per_pixel.max(1),
+            seed,
+            rng               : Pcg32::new(seed, 0),
+            pixel             : (0, 0),
+            pixel_hash        : 0,
+            index             : 0,
+            dimension         : 0,
+        }
+    }
+
+    // Starts sample number index of pixel (x, y) back at dimension 0
+    pub fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = (x, y);
+        self.pixel_hash = mix_bits(self.seed ^ (((x as u64) << 32) | (y as u64)));
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Pcg32::new(self.pixel_hash, index as u64);
+    }
+
+    pub fn get_1d(&mut self) -> f32 {
+        let dimension = self.next_dimensions(1);
+        match self.sampler_type {
+            SamplerType::Independent => self.rng.gen(),
+            SamplerType::Stratified => {
+                let stratum = permute(self.index % self.samples_per_pixel, self.samples_per_pixel, self.get_pixel_hash(dimension));
+                ((stratum as f32) + self.rng.gen::<f32>()) / (self.samples_per_pixel as f32)
+            },
+            SamplerType::Halton => self.get_halton(dimension),
+            SamplerType::Sobol => {
+                let seed = self.get_pixel_hash(dimension);
+                let index = nested_uniform_scramble(self.index, seed);
+                to_unit_float(nested_uniform_scramble(sobol_0(index), hash_u32(seed, 0)))
+            },
+            SamplerType::BlueNoise => {
+                let seed = self.get_hash(dimension);
+                let index = permute(self.index % self.samples_per_pixel, self.samples_per_pixel, seed);
+                let shift = get_blue_noise(self.pixel, hash_u32(seed, 0)) as f64;
+                (((index as f64) * R1_ALPHA) + shift).fract().min(ONE_MINUS_EPSILON as f64) as f32
+            },
+        }
+    }
+
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        match self.sampler_type {
+            SamplerType::Independent | SamplerType::Halton => (self.get_1d(), self.get_1d()),
+            SamplerType::Stratified => {
+                let dimension = self.next_dimensions(2);
+                let stratum = permute(self.index % self.samples_per_pixel, self.samples_per_pixel, self.get_pixel_hash(dimension));
+                sampling::stratify_2d(stratum, self.samples_per_pixel, self.rng.gen(), self.rng.gen())
+            },
+            SamplerType::Sobol => {
+                let dimension = self.next_dimensions(2);
+                let seed = self.get_pixel_hash(dimension);
+                let index = nested_uniform_scramble(self.index, seed);
+                let x = nested_uniform_scramble(sobol_0(index), hash_u32(seed, 0));
+                let y = nested_uniform_scramble(sobol_1(index), hash_u32(seed, 1));
+                (to_unit_float(x), to_unit_float(y))
+            },
+            SamplerType::BlueNoise => {
+                // Every pixel uses the same points and only the shift depends
+                // on the pixel, so neighboring pixels get samples as
+                // different as possible
+                let dimension = self.next_dimensions(2);
+                let seed = self.get_hash(dimension);
+                let index = permute(self.index % self.samples_per_pixel, self.samples_per_pixel, seed) as f64;
+                let shift_x = get_blue_noise(self.pixel, hash_u32(seed, 0)) as f64;
+                let shift_y = get_blue_noise(self.pixel, hash_u32(seed, 1)) as f64;
+                let u = ((index * R2_ALPHA.0) + shift_x).fract();
+                let v = ((index * R2_ALPHA.1) + shift_y).fract();
+                ((u as f32).min(ONE_MINUS_EPSILON), (v as f32).min(ONE_MINUS_EPSILON))
+            },
+        }
+    }
+
+    // Returns the first of count dimensions and moves past them
+    fn next_dimensions(&mut self, count: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += count;
+        dimension
+    }
+
+    // Per dimension hash shared by every pixel
+    fn get_hash(&self, dimension: u32) -> u32 {
+        hash_u32(mix_bits(self.seed) as u32, dimension)
+    }
+
+    // Per dimension hash of the current pixel
+    fn get_pixel_hash(&self, dimension: u32) -> u32 {
+        hash_u32(self.pixel_hash as u32, dimension)
+    }
+
+    // Radical inverse of the sample index with every digit, including the
+    // trailing zeros, run through a random permutation. Without it the
+    // first samples of high bases all land close to zero.
+    fn get_halton(&mut self, dimension: u32) -> f32 {
+        if (dimension as usize >= HALTON_PRIMES.len()) {
+            return self.rng.gen();
+        }
+        let base = HALTON_PRIMES[dimension as usize];
+        let seed = self.get_pixel_hash(dimension);
+        let inv_base = 1.0 / (base as f64);
+        let mut index = self.index;
+        let mut scale = inv_base;
+        let mut result = 0.0;
+        let mut digit_index = 0;
+        while (scale > 1e-8) {
+            let digit = permute(index % base, base, hash_u32(seed, digit_index));
+            result += (digit as f64) * scale;
+            index /= base;
+            scale *= inv_base;
+            digit_index += 1;
+        }
+        (result as f32).min(ONE_MINUS_EPSILON)
+    }
+}
+
+// Value of the blue noise mask at a pixel, with the tile offset by seed so
+// each dimension gets a different mask
+fn get_blue_noise(pixel: (u32, u32), seed: u32) -> f32 {
+    static BLUE_NOISE: std::sync::OnceLock<Vec<f32>> = std::sync::OnceLock::new();
+    let mask = BLUE_NOISE.get_or_init(build_blue_noise);
+    let x = ((pixel.0 as usize) + (seed as usize)) % BLUE_NOISE_SIZE;
+    let y = ((pixel.1 as usize) + ((seed >> 16) as usize)) % BLUE_NOISE_SIZE;
+    mask[y * BLUE_NOISE_SIZE + x]
+}
+
+// Tileable blue noise threshold mask with the void and cluster method. Cells
+// get ranks in the order they're added to a pattern, always in the largest
+// gap, so the cells below any threshold are evenly spread.
+// https://cv.ulichney.com/papers/1993-void-cluster.pdf
+fn build_blue_noise() -> Vec<f32> {
+    let size = BLUE_NOISE_SIZE;
+    let count = size * size;
+
+    // Gaussian around the origin, wrapping around the tile
+    let sigma = 1.5;
+    let mut kernel = vec![0.0f32; count];
+    for y in 0..size {
+        for x in 0..size {
+            let dx = x.min(size - x) as f32;
+            let dy = y.min(size - y) as f32;
+            kernel[y * size + x] = (-((dx * dx) + (dy * dy)) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+
+    // Energy of a cell is how crowded its neighborhood of the pattern is
+    let toggle = |pattern: &mut [bool], energy: &mut [f32], index: usize| {
+        pattern[index] = !pattern[index];
+        let sign = if (pattern[index]) { 1.0 } else { -1.0 };
+        let (cx, cy) = (index % size, index / size);
+        for y in 0..size {
+            for x in 0..size {
+                energy[y * size + x] += sign * kernel[((y + size - cy) % size) * size + ((x + size - cx) % size)];
+            }
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..count).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // Start from random points and move the most crowded one to the
+    // emptiest spot until that puts it back where it was
+    let mut rng = Pcg32::new(0, 0);
+    let initial_count = count / 10;
+    let mut pattern = vec![false; count];
+    let mut energy = vec![0.0f32; count];
+    let mut placed = 0;
+    while (placed < initial_count) {
+        let index = rng.gen_range(0..count);
+        if (!pattern[index]) {
+            toggle(&mut pattern, &mut energy, index);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        if (void == cluster) {
+            break;
+        }
+    }
+
+    // The initial points rank below the rest, most crowded first out, then
+    // the gaps are filled from the largest down
+    let mut ranks = vec![0; count];
+    {
+        let mut pattern = pattern.clone();
+        let mut energy = energy.clone();
+        for rank in (0..initial_count).rev() {
+            let cluster = tightest_cluster(&pattern, &energy);
+            toggle(&mut pattern, &mut energy, cluster);
+            ranks[cluster] = rank;
+        }
+    }
+    for rank in initial_count..count {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank;
+    }
+
+    ranks.iter().map(|&rank| ((rank as f32) + 0.5) / (count as f32)).collect()
+}
+
+// 64 bit finalizer from MurmurHash3
+fn mix_bits(v: u64) -> u64 {
+    let mut v = v;
+    v ^= v >> 33;
+    v = v.wrapping_mul(0xff51afd7ed558ccd);
+    v ^= v >> 33;
+    v = v.wrapping_mul(0xc4ceb9fe1a85ec53);
+    v ^= v >> 33;
+    v
+}
+
+fn hash_u32(a: u32, b: u32) -> u32 {
+    (mix_bits(((a as u64) << 32) | (b as u64)) >> 32) as u32
+}
+
+// Maps a 32 bit fraction to [0, 1) without rounding up to 1
+fn to_unit_float(x: u32) -> f32 {
+    ((x >> 8) as f32) / 16777216.0
+}
+
+// Random permutation of 0..count picked by seed, without building a table
+// https://graphics.pixar.com/library/MultiJitteredSampling/paper.pdf
+fn permute(i: u32, count: u32, seed: u32) -> u32 {
+    if (count <= 1) {
+        return 0;
+    }
+    let p = seed;
+    let mut w = count - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // Hash within the next power of two and try again until it lands in range
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | (p >> 27));
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if (i < count) {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % count
+}
+
+// First two dimensions of the Sobol sequence as 32 bit fractions. Together
+// they're a (0, 2) sequence, every power of two run of samples is
+// stratified in all the elementary intervals.
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_1(index: u32) -> u32 {
+    let mut index = index;
+    let mut v = 1u32 << 31;
+    let mut x = 0;
+    while (index != 0) {
+        if (index & 1 != 0) {
+            x ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    x
+}
+
+// Hash based Owen scrambling, which keeps the stratification of the Sobol
+// points. Applied to the index it shuffles the order of the samples.
+// https://jcgt.org/published/0009/04/01/paper.pdf
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
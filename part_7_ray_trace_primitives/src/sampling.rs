@@ -0,0 +1,214 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+use crate::vec3;
+use crate::vec3::*;
+
+// Helpers for Monte Carlo sampling. Directions are generated around +Z in a
+// local frame and moved to world space with an orthonormal basis.
+
+// Orthonormal basis around N
+// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+pub fn make_basis(N: Vec3) -> (Vec3, Vec3) {
+    let sign = if (N.z >= 0.0) { 1.0 } else { -1.0 };
+    let a = -1.0 / (sign + N.z);
+    let b = N.x * N.y * a;
+    let T = vec3(1.0 + sign * N.x * N.x * a, sign * b, -sign * N.x);
+    let B = vec3(b, sign + N.y * N.y * a, -N.y);
+    (T, B)
+}
+
+// Local direction to world space, with local +Z mapped to N
+pub fn local_to_world(v: Vec3, N: Vec3) -> Vec3 {
+    let (T, B) = make_basis(N);
+    (v.x * T) + (v.y * B) + (v.z * N)
+}
+
+// Cosine weighted direction on the hemisphere around +Z, pdf is cos(theta)/PI
+pub fn cosine_sample_hemisphere(u1: f32, u2: f32) -> Vec3 {
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    vec3(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt())
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) / PI
+}
+
+// Direction around +Z distributed as cos(alpha)^exponent, for sampling the
+// Phong lobe around the mirror direction
+pub fn phong_sample_lobe(u1: f32, u2: f32, exponent: f32) -> Vec3 {
+    let cos_alpha = u1.powf(1.0 / (exponent + 1.0));
+    let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    vec3(sin_alpha * phi.cos(), sin_alpha * phi.sin(), cos_alpha)
+}
+
+pub fn phong_lobe_pdf(cos_alpha: f32, exponent: f32) -> f32 {
+    if (cos_alpha <= 0.0) {
+        return 0.0;
+    }
+    (exponent + 1.0) / (2.0 * PI) * cos_alpha.powf(exponent)
+}
+
+// Microfacet normal around +Z distributed as GGX D(H) cos(H), where alpha
+// is the GGX width
+pub fn ggx_sample_half(u1: f32, u2: f32, alpha: f32) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos2_theta = (1.0 - u1) / (1.0 + (a2 - 1.0) * u1);
+    let cos_theta = cos2_theta.max(0.0).sqrt();
+    let sin_theta = (1.0 - cos2_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+// Power heuristic weight for a sample from strategy f taken nf times,
+// against strategy g taken ng times
+// https://graphics.stanford.edu/papers/veach_thesis/chapter9.pdf
+pub fn power_heuristic(nf: u32, f_pdf: f32, ng: u32, g_pdf: f32) -> f32 {
+    let f = (nf as f32) * f_pdf;
+    let g = (ng as f32) * g_pdf;
+    if (f <= 0.0) { 0.0 } else { (f * f) / ((f * f) + (g * g)) }
+}
+
+// Rec. 709 relative luminance
+pub fn luminance(c: Vec3) -> f32 {
+    vec3::dot(c, vec3(0.2126, 0.7152, 0.0722))
+}
+
+// Uniform direction inside the cone around +Z with half angle acos(cos_max),
+// pdf is 1 / cone_solid_angle(cos_max)
+pub fn uniform_sample_cone(u1: f32, u2: f32, cos_max: f32) -> Vec3 {
+    let cos_theta = 1.0 - u1 * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn cone_solid_angle(cos_max: f32) -> f32 {
+    2.0 * PI * (1.0 - cos_max)
+}
+
+// Uniform point on the unit disk in the XY plane
+pub fn uniform_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    (r * phi.cos(), r * phi.sin())
+}
+
+// Uniform point in a regular polygon with the given number of sides,
+// inscribed in the unit circle and rotated by rotation radians. u1 picks
+// the triangle between the center and one side.
+pub fn uniform_sample_polygon(u1: f32, u2: f32, sides: u32, rotation: f32) -> (f32, f32) {
+    let sides_f = sides as f32;
+    let side = ((u1 * sides_f) as u32).min(sides - 1);
+    let u1 = (u1 * sides_f) - (side as f32);
+
+    // Uniform in the triangle center, a, b
+    let angle = 2.0 * PI / sides_f;
+    let a0 = rotation + (side as f32) * angle;
+    let (a, b) = ((a0.cos(), a0.sin()), ((a0 + angle).cos(), (a0 + angle).sin()));
+    let s = u1.sqrt();
+    let (wa, wb) = (s * (1.0 - u2), s * u2);
+    ((wa * a.0) + (wb * b.0), (wa * a.1) + (wb * b.1))
+}
+
+// Moves (u1, u2) into cell i of a grid of count cells covering the unit
+// square. The grid is as square as count allows while using every cell
+// exactly once, so a prime count ends up as strips.
+pub fn stratify_2d(i: u32, count: u32, u1: f32, u2: f32) -> (f32, f32) {
+    let mut columns = (count as f32).sqrt() as u32;
+    while (columns > 1) && !count.is_multiple_of(columns) {
+        columns -= 1;
+    }
+    let columns = columns.max(1);
+    let rows = count / columns;
+    let x = i % columns;
+    let y = i / columns;
+    (((x as f32) + u1) / (columns as f32), ((y as f32) + u2) / (rows as f32))
+}
+
+// Piecewise constant distribution over [0, 1) with one bucket per function
+// value, sampled by inverting its CDF
+// https://pbr-book.org/3ed-2018/Monte_Carlo_Integration/Sampling_Random_Variables
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func     : Vec<f32>,
+    cdf      : Vec<f32>, // func.len() + 1 entries from 0 to 1
+    integral : f32,
+}
+
+impl Distribution1D {
+    // Negative values count as zero. If everything is zero the
+    // distribution is uniform.
+    pub fn new(func: &[f32]) -> Distribution1D {
+        let n = func.len().max(1);
+        let func: Vec<f32> = func.iter().map(|&f| f.max(0.0)).collect();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..func.len() {
+            cdf[i + 1] = cdf[i] + func[i] / (n as f32);
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if (integral > 0.0) { *c / integral } else { (i as f32) / (n as f32) };
+        }
+        Distribution1D { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    // Position in [0, 1) for u, the pdf there and the bucket it's in
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        // Last bucket whose cdf is <= u
+        let n = self.count().max(1);
+        let index = self.cdf.partition_point(|&c| c <= u).clamp(1, n) - 1;
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if (width > 0.0) { (u - self.cdf[index]) / width } else { 0.0 };
+        let x = (((index as f32) + offset) / (n as f32)).min(1.0 - f32::EPSILON);
+        (x, self.pdf(index), index)
+    }
+
+    // Pdf of the bucket with respect to x in [0, 1)
+    pub fn pdf(&self, index: usize) -> f32 {
+        if (self.integral > 0.0) { self.func.get(index).map_or(0.0, |&f| f / self.integral) } else { 1.0 }
+    }
+}
+
+// Piecewise constant distribution over the unit square from a grid of
+// values, width by height with rows in order. Samples pick a row from the
+// marginal distribution and then a column in that row.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    rows     : Vec<Distribution1D>,
+    marginal : Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Distribution2D {
+        let rows: Vec<Distribution1D> = (0..height).map(|y| Distribution1D::new(&func[y * width..(y + 1) * width])).collect();
+        let marginal = Distribution1D::new(&rows.iter().map(|row| row.integral()).collect::<Vec<_>>());
+        Distribution2D { rows, marginal }
+    }
+
+    // (x, y) in the unit square for (u1, u2) and the pdf there
+    pub fn sample(&self, u1: f32, u2: f32) -> (f32, f32, f32) {
+        let (y, pdf_y, row) = self.marginal.sample(u2);
+        let (x, pdf_x, _) = self.rows[row].sample(u1);
+        (x, y, pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        let row = ((y * self.marginal.count() as f32) as usize).min(self.marginal.count() - 1);
+        let columns = self.rows[row].count();
+        let column = ((x * columns as f32) as usize).min(columns - 1);
+        self.marginal.pdf(row) * self.rows[row].pdf(column)
+    }
+}
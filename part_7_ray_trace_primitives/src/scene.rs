@@ -1,44 +1,239 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
+use std::borrow::Cow;
+use std::f32::consts::PI;
+
+use crate::bvh::Bvh;
 use crate::camera::Camera;
+use crate::filter::Filter;
+use crate::hit::HitRecord;
+use crate::light::{Light, LightSample, LightType};
+use crate::material::{fresnel_dielectric, roughness_to_shininess, Material, MaterialId, ShadingModel, DEFAULT_MATERIAL};
+use crate::microfacet;
 use crate::primitives::Primitive;
+use crate::ray;
 use crate::ray::Ray;
+use crate::sampler::{Sampler, SamplerType};
+use crate::sampling;
+use crate::sky;
+use crate::sky::Sky;
+use crate::texture::{Texture, TextureId};
+use crate::vec2::vec2;
 use crate::vec3;
 use crate::vec3::*;
 
-#[derive(Default)]
+// Glossy reflection rays per primary hit on a rough Pbr material in the
+// Whitted integrator
+const GLOSSY_SAMPLES: u32 = 8;
+
+// Surfaces a shadow ray passes through before the light counts as blocked
+const MAX_SHADOW_SURFACES: u32 = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    Whitted,   // trace_recursive, deterministic apart from area lights and glossy reflections
+    PathTrace, // trace_path
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RenderSettings {
+    pub integrator        : Integrator,
+    pub samples_per_pixel : u32, // A single sample goes through the pixel center
+    pub max_depth         : u32,
+    pub seed              : u64,
+    pub filter            : Filter,
+    pub sampler           : SamplerType,
+    pub shadow_fill       : f32, // Whitted only, fraction of a blocked light that still gets through
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            integrator        : Integrator::Whitted,
+            samples_per_pixel : 16,
+            max_depth         : 6,
+            seed              : 0,
+            filter            : Filter::default(),
+            sampler           : SamplerType::Stratified,
+            shadow_fill       : 0.3,
+        }
+    }
+}
+
 pub struct Scene {
     pub camera     : Camera,
     pub primitives : Vec<Box<dyn Primitive + Sync + Send>>, // Sync + Send required sharing between threads
-    pub light      : Vec3,
+    pub materials  : Vec<Material>,
+    pub textures   : Vec<Texture>,
+    pub lights     : Vec<Light>,
+    pub ambient    : Vec3, // Whitted only, and only without a sampled sky
+    pub sky        : Sky,
+    pub settings   : RenderSettings,
+    bvh            : Option<Bvh>,
+    light_prims    : Vec<usize>, // Sorted indices of primitives sampled as lights
+    moving_prims   : bool, // Whether any primitive has an end pose
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene {
+            camera       : Camera::default(),
+            primitives   : Vec::new(),
+            materials    : Vec::new(),
+            textures     : Vec::new(),
+            lights       : Vec::new(),
+            ambient      : vec3::from_scalar(0.2),
+            sky          : sky::gradient_sky(),
+            settings     : RenderSettings::default(),
+            bvh          : None,
+            light_prims  : Vec::new(),
+            moving_prims : false,
+        }
+    }
 }
 
 impl Scene {
-    pub fn trace_closest_hit(&self, ray: Ray, hit_index: &mut usize, closest_t: &mut f32, closest_P: &mut Vec3, closest_N: &mut Vec3) -> bool {
-        *closest_t = f32::MAX;
-        *hit_index = usize::MAX;
-        for (i, prim) in self.primitives.iter().enumerate() {
-            let mut t = f32::MAX;
-            let mut P = vec3::ZERO;
-            let mut N = vec3::ZERO;
-            let hit = prim.intersect_illum(&ray, &mut t, &mut P, &mut N);
-            if (hit && (t > 0.0) && (t < *closest_t)) {
-                *hit_index = i;
-                *closest_t = t;
-                *closest_P = P;
-                *closest_N = N;
-            }
+    pub fn add_material(&mut self, material: Material) -> MaterialId {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    pub fn get_material(&self, id: MaterialId) -> &Material {
+        self.materials.get(id).unwrap_or(&DEFAULT_MATERIAL)
+    }
+
+    pub fn add_texture(&mut self, texture: Texture) -> TextureId {
+        self.textures.push(texture);
+        self.textures.len() - 1
+    }
+
+    // Missing textures are ignored, leaving the material's own value
+    fn get_texture(&self, id: Option<TextureId>) -> Option<&Texture> {
+        self.textures.get(id?)
+    }
+
+    // The hit's material with its textures looked up at the hit. Untextured
+    // materials are borrowed as they are.
+    pub fn get_surface_material(&self, hit: &HitRecord) -> Cow<'_, Material> {
+        let material = self.get_material(hit.material);
+        if (!material.has_textures()) {
+            return Cow::Borrowed(material);
+        }
+
+        let mut surface = material.clone();
+        if let Some(texture) = self.get_texture(material.albedo_texture) {
+            surface.albedo *= texture.evaluate(hit.uv, hit.P);
         }
+        if let Some(texture) = self.get_texture(material.roughness_texture) {
+            surface.roughness = texture.evaluate_scalar(hit.uv, hit.P);
+            surface.shininess = roughness_to_shininess(surface.roughness);
+        }
+        if let Some(texture) = self.get_texture(material.metallic_texture) {
+            surface.metallic = texture.evaluate_scalar(hit.uv, hit.P);
+        }
+        if let Some(texture) = self.get_texture(material.emission_texture) {
+            surface.emission *= texture.evaluate(hit.uv, hit.P);
+        }
+        Cow::Owned(surface)
+    }
+
+    // Bends the shading normal with the material's normal and bump maps. Both
+    // work in the outward facing frame, where B points the way v increases.
+    // The geometric normal is left alone for light and ray direction checks.
+    pub fn apply_normal_maps(&self, material: &Material, hit: &mut HitRecord) {
+        let normal_texture = self.get_texture(material.normal_texture);
+        let bump_texture = self.get_texture(material.bump_texture);
+        if (normal_texture.is_none() && bump_texture.is_none()) {
+            return;
+        }
+
+        let side = if (hit.front_face) { 1.0 } else { -1.0 };
+        let T = hit.T;
+        let B = side * hit.B;
+        let mut N = side * hit.N;
+        if let Some(texture) = normal_texture {
+            // Colors 0 to 1 map to -1 to 1, Z is along the normal
+            let n = (2.0 * texture.evaluate(hit.uv, hit.P)) - vec3::ONE;
+            N = (n.x * T) + (n.y * B) + (n.z * N);
+        }
+        if let Some(texture) = bump_texture {
+            let (dhdu, dhdv) = texture.evaluate_scalar_gradient(hit.uv, hit.P, T, B);
+            N -= material.bump_scale * ((dhdu * T) + (dhdv * B));
+        }
+        if (length2(N) <= 1e-12) {
+            return;
+        }
+
+        hit.set_shading_normal(normalize(N));
+        hit.set_tangent(T);
+    }
+
+    // Builds the BVH over the world space bounds of the primitives, and finds
+    // the emissive primitives to sample as lights. Must be called again after
+    // primitives are added or moved, until then tracing falls back to testing
+    // every primitive and only the scene lights light anything.
+    pub fn build_bvh(&mut self) {
+        let bounds: Vec<_> = self.primitives.iter().map(|prim| prim.get_bounds()).collect();
+        self.bvh = Some(Bvh::build(&bounds));
+
+        // Infinite primitives have no bounding sphere to sample
+        self.light_prims = (0..self.primitives.len())
+            .filter(|&i| self.get_material(self.primitives[i].get_material()).is_light() && bounds[i].is_finite())
+            .collect();
+        self.moving_prims = self.primitives.iter().any(|prim| prim.get_transform().is_moving());
+    }
 
-        if (*hit_index != usize::MAX) {
+    // Whether rays need a time, checks the primitives directly if they've
+    // changed since build_bvh()
+    fn is_moving(&self) -> bool {
+        if (self.camera.is_moving()) {
             return true;
         }
+        match self.get_bvh() {
+            Some(_) => self.moving_prims,
+            None => self.primitives.iter().any(|prim| prim.get_transform().is_moving()),
+        }
+    }
 
-        return false;
+    fn is_light_prim(&self, prim_index: usize) -> bool {
+        self.light_prims.binary_search(&prim_index).is_ok()
     }
 
+    fn get_bvh(&self) -> Option<&Bvh> {
+        self.bvh.as_ref().filter(|bvh| bvh.item_count() == self.primitives.len())
+    }
+
+    // Closest hit within the ray's interval. Each primitive is tested with
+    // t_max shrunk to the closest hit so far.
+    pub fn trace_closest_hit(&self, ray: Ray) -> Option<HitRecord> {
+        let mut closest: Option<HitRecord> = None;
+
+        if let Some(bvh) = self.get_bvh() {
+            bvh.closest_hit(&ray, |i, current_t| {
+                let hit = self.primitives[i].intersect_illum(&Ray { t_max: current_t, ..ray })?;
+                closest = Some(HitRecord { prim_index: i, ..hit });
+                Some(hit.t)
+            });
+            return closest;
+        }
+
+        for (i, prim) in self.primitives.iter().enumerate() {
+            let t_max = closest.map_or(ray.t_max, |hit| hit.t);
+            if let Some(hit) = prim.intersect_illum(&Ray { t_max, ..ray }) {
+                closest = Some(HitRecord { prim_index: i, ..hit });
+            }
+        }
+
+        closest
+    }
+
+    // True if anything is hit within the ray's interval
     pub fn trace_any_hit(&self, ray: Ray) -> bool {
+        if let Some(bvh) = self.get_bvh() {
+            return bvh.any_hit(&ray, |i| self.primitives[i].intersect_shadow(&ray));
+        }
+
         for prim in self.primitives.iter() {
             let hit = prim.intersect_shadow(&ray);
             if hit {
@@ -48,65 +243,493 @@ impl Scene {
         return false;
     }
 
-    fn phong(&self, P: Vec3, N: Vec3, V: Vec3) -> f32 {
-        let L = normalize(self.light - P);
-        let R = reflect(-L, N);
-        let d = dot(N, L).max(0.0);
-        let s = dot(R, V).max(0.0).powf(30.0);
-        let a = 0.2;
-        let c = a + (0.7 * d) + (0.3 * s);
-        return c;
+    // Fraction of light that gets through everything along the ray. Each
+    // transparent surface entered lets through its transparency and the
+    // inside absorbs per Beer-Lambert, refraction is ignored so glass casts
+    // a tinted shadow without caustics. Anything opaque blocks it all.
+    pub fn trace_transmittance(&self, ray: Ray) -> Vec3 {
+        if (!self.materials.iter().any(|material| material.transparency > 0.0)) {
+            return if (self.trace_any_hit(ray)) { vec3::ZERO } else { vec3::ONE };
+        }
+
+        let mut transmittance = vec3::ONE;
+        let mut ray = ray;
+        for _i in 0..MAX_SHADOW_SURFACES {
+            let hit = match self.trace_closest_hit(ray) {
+                Some(hit) => hit,
+                None => return transmittance,
+            };
+            let material = self.get_surface_material(&hit);
+            if (material.transparency <= 0.0) {
+                return vec3::ZERO;
+            }
+            if (hit.front_face) {
+                transmittance = material.transparency * transmittance;
+            }
+            else {
+                transmittance *= vec3::exp(-hit.t * material.absorption);
+            }
+            if (transmittance.x.max(transmittance.y).max(transmittance.z) <= 0.0) {
+                return vec3::ZERO;
+            }
+            ray = ray::spawn_segment(hit.P, ray.dir, ray.t_max - hit.t, ray.time);
+        }
+        vec3::ZERO
+    }
+
+    // Direction towards the sun: the Preetham sky's, otherwise the first
+    // directional light, otherwise straight up
+    pub fn get_sun_direction(&self) -> Vec3 {
+        if let Some(sun_dir) = self.sky.get_sun_direction() {
+            return sun_dir;
+        }
+        if let Some(sun) = self.lights.iter().find(|light| matches!(light.light_type, LightType::Directional)) {
+            return -normalize(sun.direction);
+        }
+        vec3::Y_AXIS
+    }
+
+    // Sample of the light if it's visible from the hit, on the side of its
+    // geometric normal, dimmed by transparent things in the way. A blocked
+    // light still gives shadow_fill of its radiance.
+    fn sample_visible_light(&self, light: &Light, hit: &HitRecord, shadow_fill: f32, u1: f32, u2: f32) -> Option<LightSample> {
+        let sample = light.sample(hit.P, u1, u2)?;
+        if (dot(hit.geometric_N, sample.L) <= 0.0) {
+            return None;
+        }
+
+        // Shadow, only from things in front of the light
+        let shadow_ray = ray::spawn_segment(hit.P, sample.L, sample.distance, hit.time);
+        let transmittance = self.trace_transmittance(shadow_ray);
+        let visibility = vec3::from_scalar(shadow_fill) + ((1.0 - shadow_fill) * transmittance);
+        if (visibility.x.max(visibility.y).max(visibility.z) <= 0.0) {
+            return None;
+        }
+
+        Some(LightSample { radiance: visibility * sample.radiance, ..sample })
+    }
+
+    // Sample of an emissive primitive used as a light. Directions are picked
+    // in the cone covering its bounding sphere and only count if the first
+    // thing they hit is the primitive itself, which also takes care of the
+    // shadow. Radiance is in the same units as the other lights.
+    fn sample_visible_prim(&self, prim_index: usize, hit: &HitRecord, u1: f32, u2: f32) -> Option<LightSample> {
+        let P = hit.P;
+        let bounds = self.primitives[prim_index].get_bounds();
+        let radius = 0.5 * length(bounds.extent());
+        let to_center = bounds.center() - P;
+        let center_distance2 = length2(to_center);
+        if (center_distance2 <= radius * radius) {
+            return None;
+        }
+
+        let cos_max = (1.0 - (radius * radius) / center_distance2).max(0.0).sqrt();
+        let L = sampling::local_to_world(sampling::uniform_sample_cone(u1, u2, cos_max), normalize(to_center));
+        if (dot(hit.geometric_N, L) <= 0.0) {
+            return None;
+        }
+
+        let light_hit = self.trace_closest_hit(ray::spawn_ray(P, L, hit.time))?;
+        if (light_hit.prim_index != prim_index) {
+            return None;
+        }
+
+        let emission = self.get_surface_material(&light_hit).emission;
+        let solid_angle = sampling::cone_solid_angle(cos_max);
+        Some(LightSample { L, distance: light_hit.t, radiance: (solid_angle / PI) * emission })
     }
 
-    pub fn shade(&self, hit_index: usize, P: Vec3, N: Vec3) -> Vec3 {
-        // Light
-        let V = normalize(self.camera.get_eye() - P);
-        let c = self.phong(P, N, V);
-        let color = c * *self.primitives[hit_index].get_color();
+    // Average of count stratified samples from sample_light, each weighted by
+    // what the surface reflects towards the viewer for light from sample.L
+    fn average_light_samples(&self, count: u32, sampler: &mut Sampler, sample_light: impl Fn(f32, f32) -> Option<LightSample>, reflectance: &impl Fn(Vec3) -> Vec3) -> Vec3 {
+        let mut sum = vec3::ZERO;
+        for i in 0..count {
+            let (u1, u2) = sampler.get_2d();
+            let (u1, u2) = sampling::stratify_2d(i, count, u1, u2);
+            if let Some(sample) = sample_light(u1, u2) {
+                sum += sample.radiance * reflectance(sample.L);
+            }
+        }
+        sum / (count as f32)
+    }
 
-        // Shadow
-        let mut shadow: f32 = 0.0;
-        let shadow_pos = P + (0.01 * N);
-        let shadow_dir = vec3::normalize(self.light - P);
-        let shadow_ray = Ray { pos: shadow_pos, dir: shadow_dir };
-        let hit = self.trace_any_hit(shadow_ray);
-        if (hit) {
-            shadow = 0.7;
+    // Direct light at the hit from the scene lights, the Preetham sky's sun
+    // and the emissive primitives used as lights. Light from behind the
+    // geometric normal is skipped even if the shading normal faces it, so
+    // bent normals can't light a surface through itself. The emissive
+    // primitives are lit with prim_reflectance, so Whitted can leave out the
+    // specular lobe that trace_glossy() already reflects them through.
+    fn gather_lights(&self, hit: &HitRecord, sampler: &mut Sampler, shadow_fill: f32, reflectance: impl Fn(Vec3) -> Vec3, prim_reflectance: impl Fn(Vec3) -> Vec3) -> Vec3 {
+        let mut direct = vec3::ZERO;
+        for light in self.lights.iter() {
+            direct += self.average_light_samples(light.get_sample_count(), sampler, |u1, u2| self.sample_visible_light(light, hit, shadow_fill, u1, u2), &reflectance);
+        }
+        if let Some(sun) = self.sky.get_sun_light() {
+            direct += self.average_light_samples(1, sampler, |u1, u2| self.sample_visible_light(&sun, hit, shadow_fill, u1, u2), &reflectance);
+        }
+        for &prim_index in self.light_prims.iter() {
+            let count = self.get_material(self.primitives[prim_index].get_material()).light_samples;
+            direct += self.average_light_samples(count, sampler, |u1, u2| self.sample_visible_prim(prim_index, hit, u1, u2), &prim_reflectance);
+        }
+        direct
+    }
+
+    // Light from an environment sky at the hit, averaged over
+    // sky.light_samples directions picked in proportion to its brightness.
+    // The path tracer also finds the sky by sampling the BRDF, surface_pdf
+    // is the pdf of that picking L, and both are weighted with the power
+    // heuristic. Pass zero to count these samples in full.
+    fn sample_sky(&self, hit: &HitRecord, sampler: &mut Sampler, reflectance: impl Fn(Vec3) -> Vec3, surface_pdf: impl Fn(Vec3) -> f32) -> Vec3 {
+        if (!self.sky.is_sampled()) {
+            return vec3::ZERO;
         }
 
-        return color * (1.0 - shadow);
+        let count = self.sky.light_samples;
+        let sample_light = |u1: f32, u2: f32| {
+            let (L, radiance, pdf) = self.sky.sample(u1, u2)?;
+            if (dot(hit.geometric_N, L) <= 0.0) || self.trace_any_hit(ray::spawn_ray(hit.P, L, hit.time)) {
+                return None;
+            }
+            // In the same units as the other lights
+            let weight = sampling::power_heuristic(count, pdf, 1, surface_pdf(L));
+            Some(LightSample { L, distance: f32::INFINITY, radiance: radiance * (weight / (PI * pdf)) })
+        };
+        self.average_light_samples(count, sampler, sample_light, &reflectance)
     }
 
-    pub fn trace_recursive(&self, ray: Ray, depth: u32, max_depth: u32) -> Vec3 {
+    fn phong(&self, material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+        let R = reflect(-L, N);
+        let d = dot(N, L).max(0.0);
+        let s = if (d > 0.0) { dot(R, V).max(0.0).powf(material.shininess) } else { 0.0 };
+        return (material.albedo * d) + (material.specular * s);
+    }
+
+    // Area lights and environment skies cast several shadow rays, their
+    // directions come from sampler. material is the hit's surface material.
+    // Pbr materials are lit in the same units as in the path tracer, so a
+    // white diffuse surface looks the same with either model. A sampled sky
+    // replaces the ambient term, and it and the emissive primitives only
+    // light the Pbr diffuse since trace_glossy() already picks up their
+    // reflection. V points back along the ray that found the hit. Shadows
+    // from the scene lights keep settings.shadow_fill of the light, like the
+    // original fixed 30%.
+    pub fn shade(&self, hit: &HitRecord, material: &Material, V: Vec3, sampler: &mut Sampler) -> Vec3 {
+        let N = hit.N;
+        let ambient = if (self.sky.is_sampled()) { vec3::ZERO } else { self.ambient };
+        let shadow_fill = self.settings.shadow_fill;
+        let (ambient, direct) = match material.model {
+            ShadingModel::Phong => {
+                let phong = |L| self.phong(material, N, V, L);
+                (ambient * material.albedo, self.gather_lights(hit, sampler, shadow_fill, phong, phong) + self.sample_sky(hit, sampler, phong, |_| 0.0))
+            },
+            ShadingModel::Pbr => {
+                let diffuse = (1.0 - material.metallic.clamp(0.0, 1.0)) * material.albedo;
+                let full = |L| PI * dot(N, L).max(0.0) * microfacet::eval(material, N, V, L);
+                let diffuse_only = |L| PI * dot(N, L).max(0.0) * microfacet::eval_diffuse(material, N, V, L);
+                let direct = self.gather_lights(hit, sampler, shadow_fill, full, diffuse_only);
+                let sky = self.sample_sky(hit, sampler, diffuse_only, |_| 0.0);
+                (ambient * diffuse, direct + sky)
+            },
+        };
+
+        return material.emission + ambient + direct;
+    }
+
+    pub fn trace_recursive(&self, ray: Ray, depth: u32, max_depth: u32, sampler: &mut Sampler) -> Vec3 {
         if (depth >= max_depth) {
             return vec3(0.0, 0.0, 0.0);
         }
 
-        let mut hit_index = usize::MAX;
-        let mut t = f32::MAX;
-        let mut P = vec3::ZERO;
-        let mut N = vec3::ZERO;
-        let hit = self.trace_closest_hit(ray, &mut hit_index, &mut t, &mut P, &mut N);
-        if (!hit) {
-            // Sky color
-            return 0.8 * get_sky_color(ray.dir, normalize(self.light));
+        let mut hit = match self.trace_closest_hit(ray) {
+            Some(hit) => hit,
+            None => {
+                return self.sky.evaluate(ray.dir, self.get_sun_direction());
+            },
+        };
+
+        let material = self.get_surface_material(&hit);
+        self.apply_normal_maps(&material, &mut hit);
+        let P = hit.P;
+        let N = hit.N;
+        let front_face = hit.front_face;
+
+        let mut color = self.shade(&hit, &material, -ray.dir, sampler);
+
+        if (material.model == ShadingModel::Pbr) {
+            color += (1.0 - material.transparency) * self.trace_glossy(&hit, &material, -ray.dir, depth, max_depth, sampler);
+        }
+
+        let reflectivity = material.get_mirror_reflectivity();
+        let transparency = material.transparency;
+        // Beer-Lambert, hitting the inside of a surface means the ray travelled
+        // t through the object. Only the light carried through the medium is
+        // absorbed, not the surface's own shading.
+        let absorption = if (front_face) { vec3::ONE } else { vec3::exp(-hit.t * material.absorption) };
+        if (reflectivity > 0.0) || (transparency > 0.0) {
+            let reflectionRay = ray::spawn_ray(P, hit.reflect(ray.dir), ray.time);
+            let reflection = self.trace_recursive(reflectionRay, depth + 1, max_depth, sampler);
+
+            color += reflectivity * reflection;
+
+            if (transparency > 0.0) {
+                let eta = if (front_face) { 1.0 / material.ior } else { material.ior };
+                let cos_i = -dot(ray.dir, N);
+                let fresnel = fresnel_dielectric(cos_i, eta);
+
+                // None on total internal reflection, where fresnel is 1
+                let transmission = match hit.refract(ray.dir, eta) {
+                    Some(refraction_dir) => {
+                        let refractionRay = ray::spawn_ray(P, refraction_dir, ray.time);
+                        self.trace_recursive(refractionRay, depth + 1, max_depth, sampler)
+                    },
+                    None => vec3::ZERO,
+                };
+
+                let dielectric = absorption * ((fresnel * reflection) + ((1.0 - fresnel) * transmission));
+                color = mix(color, dielectric, transparency);
+            }
         }
 
-        let reflection_pos = P + (0.01 * N);
-        let reflection_dir = normalize(vec3::reflect(ray.dir, N));
-        let reflectionRay = Ray{ pos: reflection_pos, dir: reflection_dir };
-        let reflection = self.trace_recursive(reflectionRay, depth + 1, max_depth);
+        return color;
+    }
 
-        let color = self.shade(hit_index, P, N);
+    // Reflection of the scene through a Pbr material's specular lobe. Rays
+    // spread out with roughness, so primary hits on rough materials average
+    // several of them, everything else traces one.
+    fn trace_glossy(&self, hit: &HitRecord, material: &Material, V: Vec3, depth: u32, max_depth: u32, sampler: &mut Sampler) -> Vec3 {
+        let count = if (depth == 0) && (material.roughness > 0.1) { GLOSSY_SAMPLES } else { 1 };
+        let mut sum = vec3::ZERO;
+        for i in 0..count {
+            let (u1, u2) = sampler.get_2d();
+            let (u1, u2) = sampling::stratify_2d(i, count, u1, u2);
+            if let Some((L, weight)) = microfacet::sample_specular(material, hit.N, V, u1, u2) {
+                // Bent normals can send samples into the surface
+                if (dot(L, hit.geometric_N) > 0.0) {
+                    sum += weight * self.trace_recursive(ray::spawn_ray(hit.P, L, hit.time), depth + 1, max_depth, sampler);
+                }
+            }
+        }
+        sum / (count as f32)
+    }
 
-        return color + 0.5 * reflection;
+    pub fn get_samples_per_pixel(&self) -> u32 {
+        self.settings.samples_per_pixel.max(1)
     }
-}
 
-// https://www.shadertoy.com/view/tl23Rm
-fn get_sky_color(dir: Vec3, L: Vec3) -> Vec3 {
-    let mut color = mix(vec3(1.0, 1.0, 1.0), vec3(0.5, 0.7, 1.0), 0.5 + 0.5*dir.y);
-    let sun = dot(L, dir).clamp(0.0, 1.0);
-    color += (vec3(1.0, 0.6, 0.1) * sun.powf(4.0)) + vec3::from_scalar(10.0 * sun.powf(32.0));
-    return color;
-}
\ No newline at end of file
+    pub fn create_sampler(&self) -> Sampler {
+        Sampler::new(self.settings.sampler, self.get_samples_per_pixel(), self.settings.seed)
+    }
+
+    // Film position of the sample sampler was started on for pixel (x, y),
+    // from its first two dimensions
+    pub fn get_film_position(&self, x: u32, y: u32, sampler: &mut Sampler) -> (f32, f32) {
+        if (self.get_samples_per_pixel() == 1) {
+            return ((x as f32) + 0.5, (y as f32) + 0.5);
+        }
+        let (u, v) = sampler.get_2d();
+        ((x as f32) + u, (y as f32) + v)
+    }
+
+    // One sample at film position (x, y), in pixels, using the integrator from
+    // settings. The ray is cast at a random time while the shutter is open,
+    // if anything moves.
+    pub fn trace_sample(&self, x: f32, y: f32, width: u32, height: u32, sampler: &mut Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let time = self.camera.get_shutter_time(if (self.is_moving()) { sampler.get_1d() } else { 0.0 });
+        let ray = match self.camera.at_time(time).generate_lens_ray(vec2(x / (width as f32), y / (height as f32)), u1, u2) {
+            Some(ray) => Ray { time, ..ray },
+            None => return vec3::ZERO,
+        };
+        match self.settings.integrator {
+            Integrator::Whitted => self.trace_recursive(ray, 0, self.settings.max_depth, sampler),
+            Integrator::PathTrace => self.trace_path(ray, sampler),
+        }
+    }
+
+    // Average of get_samples_per_pixel() samples of pixel (x, y), without
+    // the reconstruction filter
+    pub fn trace_pixel(&self, x: u32, y: u32, width: u32, height: u32, sampler: &mut Sampler) -> Vec3 {
+        let samples = self.get_samples_per_pixel();
+        let mut color = vec3::ZERO;
+        for i in 0..samples {
+            sampler.start_pixel_sample(x, y, i);
+            let (film_x, film_y) = self.get_film_position(x, y, sampler);
+            color += self.trace_sample(film_x, film_y, width, height, sampler);
+        }
+        color / (samples as f32)
+    }
+
+    // BRDF of the material's shading model
+    fn eval_surface(&self, material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+        match material.model {
+            ShadingModel::Phong => self.eval_phong(material, N, V, L),
+            ShadingModel::Pbr => microfacet::eval(material, N, V, L),
+        }
+    }
+
+    // Normalized Phong BRDF: Lambert diffuse plus a specular lobe around the
+    // mirror direction of V
+    fn eval_phong(&self, material: &Material, N: Vec3, V: Vec3, L: Vec3) -> Vec3 {
+        let R = reflect(-V, N);
+        let n = material.shininess;
+        let s = dot(R, L).max(0.0).powf(n);
+        (material.albedo / PI) + (material.specular * ((n + 2.0) / (2.0 * PI)) * s)
+    }
+
+    // Probability of sample_surface() picking the Phong specular lobe over
+    // the diffuse, None if the material reflects nothing
+    fn phong_specular_probability(&self, material: &Material) -> Option<f32> {
+        let diffuse_weight = sampling::luminance(material.albedo);
+        let specular_weight = sampling::luminance(material.specular);
+        if (diffuse_weight + specular_weight) <= 0.0 {
+            return None;
+        }
+        Some(specular_weight / (diffuse_weight + specular_weight))
+    }
+
+    // Pdf of sample_surface() returning L
+    fn pdf_surface(&self, material: &Material, N: Vec3, V: Vec3, L: Vec3) -> f32 {
+        if (material.model == ShadingModel::Pbr) {
+            return microfacet::pdf(material, N, V, L);
+        }
+
+        let cos_theta = dot(N, L);
+        let specular_prob = match self.phong_specular_probability(material) {
+            Some(specular_prob) if (cos_theta > 0.0) => specular_prob,
+            _ => return 0.0,
+        };
+        let R = reflect(-V, N);
+        ((1.0 - specular_prob) * sampling::cosine_hemisphere_pdf(cos_theta)) + (specular_prob * sampling::phong_lobe_pdf(dot(R, L), material.shininess))
+    }
+
+    // Picks the diffuse or the specular lobe in proportion to their weights and
+    // samples a direction from it. Returns the direction, the BRDF times
+    // cosine over the combined pdf and the pdf, or None if the sample is below
+    // the surface.
+    fn sample_surface(&self, material: &Material, N: Vec3, V: Vec3, sampler: &mut Sampler) -> Option<(Vec3, Vec3, f32)> {
+        if (material.model == ShadingModel::Pbr) {
+            let (u1, u2) = sampler.get_2d();
+            return microfacet::sample(material, N, V, u1, u2, sampler.get_1d());
+        }
+
+        let specular_prob = self.phong_specular_probability(material)?;
+        let R = reflect(-V, N);
+        let n = material.shininess;
+        let (u1, u2) = sampler.get_2d();
+        let L = if (sampler.get_1d() < specular_prob) {
+            sampling::local_to_world(sampling::phong_sample_lobe(u1, u2, n), R)
+        }
+        else {
+            sampling::local_to_world(sampling::cosine_sample_hemisphere(u1, u2), N)
+        };
+
+        let cos_theta = dot(N, L);
+        if (cos_theta <= 0.0) {
+            return None;
+        }
+
+        let pdf = self.pdf_surface(material, N, V, L);
+        if (pdf <= 0.0) {
+            return None;
+        }
+
+        Some((L, self.eval_phong(material, N, V, L) * (cos_theta / pdf), pdf))
+    }
+
+    // Next event estimation to every light and the sky. Intensities are
+    // scaled by PI so a white diffuse surface reflects the same as it does in
+    // shade().
+    fn sample_lights(&self, material: &Material, hit: &HitRecord, V: Vec3, sampler: &mut Sampler) -> Vec3 {
+        let N = hit.N;
+        let reflectance = |L| PI * dot(N, L).max(0.0) * self.eval_surface(material, N, V, L);
+        self.gather_lights(hit, sampler, 0.0, reflectance, reflectance) + self.sample_sky(hit, sampler, reflectance, |L| self.pdf_surface(material, N, V, L))
+    }
+
+    // Unidirectional path tracer with next event estimation and Russian
+    // roulette. Each bounce picks one part of the material at random: the
+    // dielectric with probability transparency, otherwise the mirror with
+    // probability reflectivity, otherwise the material's BRDF. The ambient
+    // term is ignored, indirect light comes from the sky and other surfaces
+    // instead. Emissive primitives used as lights are already counted by
+    // next event estimation, so hitting them after a BRDF bounce adds
+    // nothing. A sampled sky is found both ways and weighted between them.
+    pub fn trace_path(&self, ray: Ray, sampler: &mut Sampler) -> Vec3 {
+        let mut ray = ray;
+        let mut throughput = vec3::ONE;
+        let mut radiance = vec3::ZERO;
+        // Pdf of the BRDF sample the ray came from, None for camera rays
+        // and mirror or dielectric bounces. Lights were sampled when it's set.
+        let mut surface_pdf: Option<f32> = None;
+
+        for depth in 0..self.settings.max_depth {
+            let mut hit = match self.trace_closest_hit(ray) {
+                Some(hit) => hit,
+                None => {
+                    let weight = match surface_pdf {
+                        Some(pdf) if (self.sky.is_sampled()) => sampling::power_heuristic(1, pdf, self.sky.light_samples, self.sky.pdf(ray.dir)),
+                        _ => 1.0,
+                    };
+                    radiance += throughput * weight * self.sky.evaluate(ray.dir, self.get_sun_direction());
+                    break;
+                },
+            };
+
+            let material = self.get_surface_material(&hit);
+            self.apply_normal_maps(&material, &mut hit);
+            let P = hit.P;
+            let N = hit.N;
+            let front_face = hit.front_face;
+
+            // Beer-Lambert, hitting the inside of a surface means the ray travelled t through the object
+            if (!front_face) {
+                throughput *= vec3::exp(-hit.t * material.absorption);
+            }
+
+            if (surface_pdf.is_none() || !self.is_light_prim(hit.prim_index)) {
+                radiance += throughput * material.emission;
+            }
+            surface_pdf = None;
+
+            let V = -ray.dir;
+            let u = sampler.get_1d();
+            let mirror_prob = (1.0 - material.transparency) * material.get_mirror_reflectivity();
+            if (u < material.transparency) {
+                // Dielectric, reflect or refract in proportion to fresnel
+                let eta = if (front_face) { 1.0 / material.ior } else { material.ior };
+                let fresnel = fresnel_dielectric(dot(V, N), eta);
+                ray = match hit.refract(ray.dir, eta) {
+                    Some(refraction_dir) if (sampler.get_1d() >= fresnel) => ray::spawn_ray(P, refraction_dir, ray.time),
+                    _ => ray::spawn_ray(P, hit.reflect(ray.dir), ray.time),
+                };
+            }
+            else if (u < material.transparency + mirror_prob) {
+                ray = ray::spawn_ray(P, hit.reflect(ray.dir), ray.time);
+            }
+            else {
+                radiance += throughput * self.sample_lights(&material, &hit, V, sampler);
+
+                // Directions the shading normal allows can still be behind
+                // the actual surface, those end the path
+                match self.sample_surface(&material, N, V, sampler) {
+                    Some((L, weight, pdf)) if (dot(L, hit.geometric_N) > 0.0) => {
+                        throughput *= weight;
+                        surface_pdf = Some(pdf);
+                        ray = ray::spawn_ray(P, L, ray.time);
+                    },
+                    _ => break,
+                }
+            }
+
+            // Russian roulette once the path has had a few bounces
+            if (depth >= 3) {
+                let survive_prob = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+                if (sampler.get_1d() >= survive_prob) {
+                    break;
+                }
+                throughput = throughput / survive_prob;
+            }
+        }
+
+        radiance
+    }
+}
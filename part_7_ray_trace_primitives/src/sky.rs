@@ -0,0 +1,311 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+use crate::image::Image;
+use crate::light;
+use crate::light::Light;
+use crate::sampling;
+use crate::sampling::Distribution2D;
+use crate::vec3;
+use crate::vec3::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SkyModel {
+    Gradient,    // Procedural blue gradient with a sun towards the first light
+    Environment, // Equirectangular image, usually HDR
+    Preetham,    // Analytic daylight from the sun position and turbidity, with the sun as a directional light
+}
+
+// What rays that miss everything see. An environment image is also sampled
+// as a light, in proportion to its brightness, so small bright areas like
+// the sun light the scene without much noise. The Preetham sky is sampled
+// the same way from a table of its values, and its sun is a directional
+// light added to the scene's lights, so shadows always match the sky.
+//
+// Environment images are equirectangular, mapped the same way as the
+// sphere's UVs: u goes around the Y axis starting and ending at -Z, and v
+// goes from straight down at the bottom to straight up at the top.
+pub struct Sky {
+    pub model         : SkyModel,
+    pub intensity     : f32,
+    pub rotation      : f32, // Degrees around the Y axis
+    pub light_samples : u32, // Environment and Preetham, shadow rays per shaded point, 0 turns off sampling it as a light
+    pub sun_elevation : f32, // Preetham, degrees above the horizon
+    pub sun_azimuth   : f32, // Preetham, degrees around the Y axis from +Z towards +X
+    pub turbidity     : f32, // Preetham, haziness from 2 for clear skies to 10 for hazy ones
+    pub file_path     : Option<String>, // Source of the image, if loaded from a file
+    image             : Option<Image>, // Environment image, or the table of Preetham values
+    distribution      : Option<Distribution2D>, // Brightness of the image texels times the solid angle they cover
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky {
+            model         : SkyModel::Gradient,
+            intensity     : 1.0,
+            rotation      : 0.0,
+            light_samples : 16,
+            sun_elevation : 45.0,
+            sun_azimuth   : 0.0,
+            turbidity     : 3.0,
+            file_path     : None,
+            image         : None,
+            distribution  : None,
+        }
+    }
+}
+
+pub fn gradient_sky() -> Sky {
+    Sky::default()
+}
+
+// Rows near the poles cover less solid angle
+fn build_distribution(image: &Image) -> Distribution2D {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let mut func = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let sin_theta = (PI * ((y as f32) + 0.5) / (height as f32)).sin();
+        for x in 0..width {
+            func.push(sampling::luminance(image.get_pixel(x as u32, y as u32)) * sin_theta);
+        }
+    }
+    Distribution2D::new(&func, width, height)
+}
+
+pub fn environment_sky(image: Image, intensity: f32, rotation: f32) -> Sky {
+    Sky {
+        model        : SkyModel::Environment,
+        intensity,
+        rotation,
+        distribution : Some(build_distribution(&image)),
+        image        : Some(image),
+        ..Default::default()
+    }
+}
+
+// Angles in degrees. The sampling table is made here, so changing the sun
+// or turbidity afterwards only makes sampling the sky less effective. It's
+// made before any rotation, which sampling applies like for environments.
+pub fn preetham_sky(sun_elevation: f32, sun_azimuth: f32, turbidity: f32, intensity: f32) -> Sky {
+    let mut sky = Sky { model: SkyModel::Preetham, sun_elevation, sun_azimuth, turbidity, intensity, ..Default::default() };
+    let mut table = Image::new(PREETHAM_TABLE_WIDTH, PREETHAM_TABLE_WIDTH / 2);
+    for y in 0..table.height {
+        for x in 0..table.width {
+            let u = ((x as f32) + 0.5) / (table.width as f32);
+            let row = ((y as f32) + 0.5) / (table.height as f32);
+            table.set_pixel(x, y, sky.get_preetham_color(image_to_direction(u, row)));
+        }
+    }
+    sky.distribution = Some(build_distribution(&table));
+    sky.image = Some(table);
+    sky
+}
+
+fn rotate_y(v: Vec3, angle: f32) -> Vec3 {
+    let (s, c) = angle.sin_cos();
+    vec3((c * v.x) + (s * v.z), v.y, (c * v.z) - (s * v.x))
+}
+
+// Sampling table resolution, the sky is smooth enough not to need much
+const PREETHAM_TABLE_WIDTH: u32 = 128;
+
+// Scale from the model's luminance in kcd/m^2 to scene units, and the sun's
+// irradiance outside the atmosphere in the same units. Together they give
+// roughly the ratio of direct sunlight to skylight of a real clear day.
+const PREETHAM_SKY_SCALE: f32 = 0.1;
+const PREETHAM_SUN_IRRADIANCE: f32 = 8.0;
+
+// How much of the horizon's color the ground below it reflects
+const GROUND_ALBEDO: f32 = 0.3;
+
+// Image coordinates of a direction in the environment's own frame, with y
+// going down from the top like the image rows
+fn direction_to_image(dir: Vec3) -> (f32, f32) {
+    let u = 0.5 + dir.x.atan2(dir.z) / (2.0 * PI);
+    let y = dir.y.clamp(-1.0, 1.0).acos() / PI;
+    (u, y)
+}
+
+fn image_to_direction(u: f32, y: f32) -> Vec3 {
+    let phi = 2.0 * PI * (u - 0.5);
+    let (sin_theta, cos_theta) = (PI * y).sin_cos();
+    vec3(sin_theta * phi.sin(), cos_theta, sin_theta * phi.cos())
+}
+
+impl Sky {
+    pub fn get_image(&self) -> Option<&Image> {
+        self.image.as_ref()
+    }
+
+    // Environment and Preetham skies are sampled as lights
+    pub fn is_sampled(&self) -> bool {
+        (self.model != SkyModel::Gradient) && self.distribution.is_some() && (self.light_samples > 0)
+    }
+
+    // Turns environment images and the Preetham sky, the gradient sky
+    // follows the sun direction it's given instead
+    fn get_rotation(&self) -> f32 {
+        if (self.model == SkyModel::Gradient) { 0.0 } else { self.rotation.to_radians() }
+    }
+
+    // Direction towards the Preetham sky's sun. The rotation turns it around
+    // the Y axis, which adds to the azimuth.
+    pub fn get_sun_direction(&self) -> Option<Vec3> {
+        if (self.model != SkyModel::Preetham) {
+            return None;
+        }
+        let elevation = self.sun_elevation.to_radians();
+        let azimuth = self.sun_azimuth.to_radians();
+        let sun_dir = vec3(elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos());
+        Some(rotate_y(sun_dir, self.get_rotation()))
+    }
+
+    // The Preetham sky's sun as a directional light, colored by how much of
+    // it gets through the atmosphere. None below the horizon.
+    pub fn get_sun_light(&self) -> Option<Light> {
+        let sun_dir = self.get_sun_direction()?;
+        if (sun_dir.y <= 0.0) {
+            return None;
+        }
+        let color = get_sun_transmittance(sun_dir.y, self.turbidity);
+        Some(light::directional_light(-sun_dir, color, self.intensity * PREETHAM_SUN_IRRADIANCE))
+    }
+
+    // Radiance seen looking along dir. sun_dir is the direction of the
+    // gradient sky's sun.
+    pub fn evaluate(&self, dir: Vec3, sun_dir: Vec3) -> Vec3 {
+        match self.model {
+            SkyModel::Gradient => self.intensity * 0.8 * get_gradient_color(dir, sun_dir),
+            SkyModel::Environment => match &self.image {
+                Some(image) => {
+                    let (u, y) = direction_to_image(rotate_y(dir, -self.get_rotation()));
+                    self.intensity * sample_bilinear(image, u, y)
+                },
+                None => vec3::ZERO,
+            },
+            SkyModel::Preetham => self.intensity * self.get_preetham_color(dir),
+        }
+    }
+
+    // Perez et al. sky luminance and chromaticity distributions with
+    // Preetham et al.'s fit to turbidity, converted to linear RGB. The sun
+    // itself isn't included, that's the directional light. Below the horizon
+    // is a dim ground lit by the sky.
+    // https://courses.cs.duke.edu/fall01/cps124/resources/p91-preetham.pdf
+    fn get_preetham_color(&self, dir: Vec3) -> Vec3 {
+        let sun_dir = match self.get_sun_direction() {
+            Some(sun_dir) => sun_dir,
+            None => return vec3::ZERO,
+        };
+        let T = self.turbidity.clamp(2.0, 10.0);
+        // Zenith angle of the sun, the model stops at the horizon
+        let theta_s = sun_dir.y.clamp(0.0, 1.0).acos();
+
+        let chi = ((4.0 / 9.0) - (T / 120.0)) * (PI - 2.0 * theta_s);
+        let Y_z = ((4.0453 * T - 4.9710) * chi.tan()) - (0.2155 * T) + 2.4192;
+        let theta = [theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0];
+        let poly = |c: [f32; 4]| (c[0] * theta[0]) + (c[1] * theta[1]) + (c[2] * theta[2]) + (c[3] * theta[3]);
+        let x_z = (T * T * poly([0.00166, -0.00375, 0.00209, 0.0])) + (T * poly([-0.02903, 0.06377, -0.03202, 0.00394])) + poly([0.11693, -0.21196, 0.06052, 0.25886]);
+        let y_z = (T * T * poly([0.00275, -0.00610, 0.00317, 0.0])) + (T * poly([-0.04214, 0.08970, -0.04153, 0.00516])) + poly([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let perez_Y = [0.1787 * T - 1.4630, -0.3554 * T + 0.4275, -0.0227 * T + 5.3251, 0.1206 * T - 2.5771, -0.0670 * T + 0.3703];
+        let perez_x = [-0.0193 * T - 0.2592, -0.0665 * T + 0.0008, -0.0004 * T + 0.2125, -0.0641 * T - 0.8989, -0.0033 * T + 0.0452];
+        let perez_y = [-0.0167 * T - 0.2608, -0.0950 * T + 0.0092, -0.0079 * T + 0.2102, -0.0441 * T - 1.6537, -0.0109 * T + 0.0529];
+
+        // Directions below the horizon see the horizon's color, darkened
+        let (cos_theta, ground) = if (dir.y > 0.0) { (dir.y.max(0.01), 1.0) } else { (0.01, GROUND_ALBEDO) };
+        let gamma = dot(normalize(vec3(dir.x, cos_theta, dir.z)), sun_dir).clamp(-1.0, 1.0).acos();
+        let perez = |c: [f32; 5], cos_theta: f32, gamma: f32| {
+            (1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+        };
+        let relative = |c: [f32; 5]| perez(c, cos_theta, gamma) / perez(c, 1.0, theta_s);
+
+        let Y = PREETHAM_SKY_SCALE * Y_z * relative(perez_Y);
+        let x = x_z * relative(perez_x);
+        let y = y_z * relative(perez_y);
+
+        // xyY to XYZ to linear sRGB
+        let X = (x / y) * Y;
+        let Z = ((1.0 - x - y) / y) * Y;
+        let rgb = vec3(
+            (3.2406 * X) - (1.5372 * Y) - (0.4986 * Z),
+            (-0.9689 * X) + (1.8758 * Y) + (0.0415 * Z),
+            (0.0557 * X) - (0.2040 * Y) + (1.0570 * Z),
+        );
+        ground * vec3::max(rgb, vec3::ZERO)
+    }
+
+    // Direction towards the environment picked in proportion to its
+    // brightness, with the radiance from there and the pdf with respect to
+    // solid angle
+    pub fn sample(&self, u1: f32, u2: f32) -> Option<(Vec3, Vec3, f32)> {
+        let distribution = self.distribution.as_ref()?;
+        let (u, y, pdf) = distribution.sample(u1, u2);
+        let sin_theta = (PI * y).sin();
+        if (pdf <= 0.0) || (sin_theta <= 0.0) {
+            return None;
+        }
+        let L = rotate_y(image_to_direction(u, y), self.get_rotation());
+        Some((L, self.evaluate(L, vec3::Y_AXIS), pdf / (2.0 * PI * PI * sin_theta)))
+    }
+
+    // Pdf of sample() returning dir
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let distribution = match &self.distribution {
+            Some(distribution) => distribution,
+            None => return 0.0,
+        };
+        let (u, y) = direction_to_image(rotate_y(dir, -self.get_rotation()));
+        let sin_theta = (PI * y).sin();
+        if (sin_theta <= 0.0) {
+            return 0.0;
+        }
+        distribution.pdf(u.rem_euclid(1.0), y) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+// Texel centers are at half integers. Wraps around horizontally and clamps
+// at the poles.
+fn sample_bilinear(image: &Image, u: f32, y: f32) -> Vec3 {
+    let (width, height) = (image.width as i64, image.height as i64);
+    let x = (u * width as f32) - 0.5;
+    let y = (y * height as f32) - 0.5;
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+    let texel = |x: i64, y: i64| image.get_pixel(x.rem_euclid(width) as u32, y.clamp(0, height - 1) as u32);
+
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = vec3::mix(texel(x0, y0), texel(x0 + 1, y0), fx);
+    let bottom = vec3::mix(texel(x0, y0 + 1), texel(x0 + 1, y0 + 1), fx);
+    vec3::mix(top, bottom, fy)
+}
+
+// Fraction of sunlight at the red, green and blue wavelengths that makes it
+// through Rayleigh and aerosol scattering, cos_theta is the cosine of the
+// sun's zenith angle. Kasten's air mass and Angstrom's turbidity formula
+// with Preetham et al.'s relation between beta and turbidity.
+fn get_sun_transmittance(cos_theta: f32, turbidity: f32) -> Vec3 {
+    let theta = cos_theta.clamp(0.0, 1.0).acos().to_degrees();
+    let air_mass = 1.0 / (cos_theta.max(0.0) + 0.15 * (93.885 - theta).powf(-1.253));
+    let beta = (0.04608 * turbidity.clamp(2.0, 10.0)) - 0.04586;
+    // Wavelengths in micrometers
+    let transmittance = |lambda: f32| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    };
+    vec3(transmittance(0.65), transmittance(0.57), transmittance(0.475))
+}
+
+// https://www.shadertoy.com/view/tl23Rm
+fn get_gradient_color(dir: Vec3, L: Vec3) -> Vec3 {
+    let mut color = mix(vec3(1.0, 1.0, 1.0), vec3(0.5, 0.7, 1.0), 0.5 + 0.5*dir.y);
+    let sun = dot(L, dir).clamp(0.0, 1.0);
+    color += (vec3(1.0, 0.6, 0.1) * sun.powf(4.0)) + vec3::from_scalar(10.0 * sun.powf(32.0));
+    color
+}
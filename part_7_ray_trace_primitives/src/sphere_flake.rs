@@ -1,21 +1,28 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+use crate::material::MaterialId;
 use crate::primitives::{Primitive, Sphere};
 use crate::vec3::Vec3;
 use crate::{transform, vec4};
 use crate::{quat, vec3};
 
+// Settings shared by every level of the recursion
+pub struct SphereFlakeParams {
+    pub maxLevels : u32,
+    pub material  : MaterialId,
+}
+
 pub fn generate_sphere_flake(
     level: u32,
-    maxLevels: u32,
+    params: &SphereFlakeParams,
     childRadius: f32,
     parentRadius: f32,
     parentCenter: Vec3,
     parentOrientation: Vec3,
     primitives: &mut Vec<Box<dyn Primitive + Sync + Send>>,
 ) {
-    if (level >= maxLevels) {
+    if (level >= params.maxLevels) {
         return;
     }
 
@@ -44,13 +51,13 @@ pub fn generate_sphere_flake(
 
         primitives.push(Box::new(Sphere {
             transform: transform::transform(offset, vec3::ZERO, vec3::ONE),
-            color: vec3(0.7, 0.7, 0.85),
+            material: params.material,
         }));
 
         let center = offset;
         generate_sphere_flake(
             level + 1,
-            maxLevels,
+            params,
             childRadius / 3.0,
             childRadius,
             center,
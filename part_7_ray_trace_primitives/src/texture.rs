@@ -0,0 +1,167 @@
+#![allow(dead_code)]
+#![allow(unused_parens)]
+#![allow(non_snake_case)]
+
+use std::f32::consts::PI;
+
+use crate::image::Image;
+use crate::noise;
+use crate::sampling;
+use crate::vec2::{vec2, Vec2};
+use crate::vec3;
+use crate::vec3::*;
+
+// Index into Scene::textures
+pub type TextureId = usize;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TextureType {
+    Image,
+    Checker,  // Squares of color0 and color1
+    Stripes,  // Bands across u
+    Gradient, // color0 to color1 along u
+    Noise,    // fBm blend between color0 and color1
+    Marble,   // Turbulent bands along X
+    Wood,     // Noisy rings around the Y axis
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+}
+
+// Image, checker, stripes and gradient textures are looked up with the
+// surface UV times scale. Noise, marble and wood are solid textures of the
+// world space position times scale, so they don't depend on the UVs and
+// carry on through the inside of an object.
+pub struct Texture {
+    pub name         : String,
+    pub texture_type : TextureType,
+    pub color0       : Vec3,
+    pub color1       : Vec3,
+    pub scale        : f32, // Repeats per UV or world unit
+    pub octaves      : u32, // Noise, marble and wood
+    pub wrap         : WrapMode, // Image and gradient
+    pub image        : Option<Image>,
+    pub file_path    : Option<String>, // Source of the image, if loaded from a file
+}
+
+impl Default for Texture {
+    fn default() -> Self {
+        Texture {
+            name         : String::new(),
+            texture_type : TextureType::Checker,
+            color0       : vec3::ONE,
+            color1       : vec3::ZERO,
+            scale        : 1.0,
+            octaves      : 4,
+            wrap         : WrapMode::Repeat,
+            image        : None,
+            file_path    : None,
+        }
+    }
+}
+
+pub fn image_texture(image: Image, wrap: WrapMode) -> Texture {
+    Texture { texture_type: TextureType::Image, image: Some(image), wrap, ..Default::default() }
+}
+
+pub fn procedural_texture(texture_type: TextureType, color0: Vec3, color1: Vec3, scale: f32) -> Texture {
+    Texture { texture_type, color0, color1, scale, ..Default::default() }
+}
+
+pub fn checker_texture(color0: Vec3, color1: Vec3, scale: f32) -> Texture {
+    procedural_texture(TextureType::Checker, color0, color1, scale)
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+impl Texture {
+    pub fn evaluate(&self, uv: Vec2, P: Vec3) -> Vec3 {
+        let u = uv.x * self.scale;
+        let v = uv.y * self.scale;
+        let P = self.scale * P;
+        match self.texture_type {
+            TextureType::Image => match &self.image {
+                Some(image) => self.sample_bilinear(image, u, v),
+                None => self.color0,
+            },
+            TextureType::Checker => {
+                let even = ((u.floor() + v.floor()) as i64).rem_euclid(2) == 0;
+                if (even) { self.color0 } else { self.color1 }
+            },
+            TextureType::Stripes => {
+                if (fract(u) < 0.5) { self.color0 } else { self.color1 }
+            },
+            TextureType::Gradient => {
+                let t = match self.wrap {
+                    WrapMode::Repeat => fract(u),
+                    WrapMode::Clamp => u.clamp(0.0, 1.0),
+                };
+                vec3::mix(self.color0, self.color1, t)
+            },
+            TextureType::Noise => {
+                let t = 0.5 + 0.5 * noise::fbm(P, self.octaves);
+                vec3::mix(self.color0, self.color1, t.clamp(0.0, 1.0))
+            },
+            TextureType::Marble => {
+                let t = 0.5 + 0.5 * (PI * (P.x + 4.0 * noise::turbulence(P, self.octaves))).sin();
+                vec3::mix(self.color0, self.color1, t)
+            },
+            TextureType::Wood => {
+                // Rings get darker towards their outer edge
+                let rings = (P.x * P.x + P.z * P.z).sqrt() + 0.5 * noise::fbm(P, self.octaves);
+                let t = fract(rings);
+                vec3::mix(self.color0, self.color1, t * t)
+            },
+        }
+    }
+
+    // Grayscale value for scalar properties like roughness
+    pub fn evaluate_scalar(&self, uv: Vec2, P: Vec3) -> f32 {
+        sampling::luminance(self.evaluate(uv, P))
+    }
+
+    // Central differences of evaluate_scalar() along u and v for bump
+    // mapping. Solid textures don't use the UVs, so for those P moves the
+    // same distance along T and B instead.
+    pub fn evaluate_scalar_gradient(&self, uv: Vec2, P: Vec3, T: Vec3, B: Vec3) -> (f32, f32) {
+        // About half a texel for images, UVs are multiplied by scale
+        let delta = match &self.image {
+            Some(image) if (self.texture_type == TextureType::Image) => 0.5 / (self.scale * image.width.max(image.height) as f32),
+            _ => 1e-3 / self.scale,
+        };
+        let du = vec2(delta, 0.0);
+        let dv = vec2(0.0, delta);
+        let dhdu = self.evaluate_scalar(uv + du, P + delta * T) - self.evaluate_scalar(uv - du, P - delta * T);
+        let dhdv = self.evaluate_scalar(uv + dv, P + delta * B) - self.evaluate_scalar(uv - dv, P - delta * B);
+        (dhdu / (2.0 * delta), dhdv / (2.0 * delta))
+    }
+
+    fn get_texel(&self, image: &Image, x: i64, y: i64) -> Vec3 {
+        let (width, height) = (image.width as i64, image.height as i64);
+        let (x, y) = match self.wrap {
+            WrapMode::Repeat => (x.rem_euclid(width), y.rem_euclid(height)),
+            WrapMode::Clamp => (x.clamp(0, width - 1), y.clamp(0, height - 1)),
+        };
+        image.get_pixel(x as u32, y as u32)
+    }
+
+    // Texel centers are at half integers, and v = 0 is the bottom of the image
+    fn sample_bilinear(&self, image: &Image, u: f32, v: f32) -> Vec3 {
+        let x = (u * image.width as f32) - 0.5;
+        let y = ((1.0 - v) * image.height as f32) - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = vec3::mix(self.get_texel(image, x0, y0), self.get_texel(image, x0 + 1, y0), fx);
+        let bottom = vec3::mix(self.get_texel(image, x0, y0 + 1), self.get_texel(image, x0 + 1, y0 + 1), fx);
+        vec3::mix(top, bottom, fy)
+    }
+}
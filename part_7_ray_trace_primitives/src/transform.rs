@@ -1,10 +1,25 @@
 #![allow(dead_code)]
+#![allow(non_snake_case)]
 
-use crate::{mat4, vec3, vec4};
+use std::borrow::Cow;
+
+use crate::{mat4, quat, vec3, vec4};
+use crate::aabb::Aabb;
+use crate::quat::Quat;
+use crate::ray::Ray;
 use crate::vec3::*;
 use crate::mat4::*;
 use crate::vec4::as_vec4;
 
+// Bounds of a moving transform are the union of the bounds at this many
+// steps across the motion, grown to cover the arcs rotation sweeps out
+// between them
+const MOTION_BOUNDS_STEPS: u32 = 32;
+
+// A moving transform goes from its translate()/rotate()/scale() pose at time
+// 0 to its end pose at time 1 and stays put outside that. Translation and
+// scale are interpolated linearly and rotation along the shortest arc.
+#[derive(Clone)]
 pub struct Transform {
     translation          : Vec3,
     rotation             : Vec3,
@@ -14,6 +29,12 @@ pub struct Transform {
     scale_matrix         : Mat4,
     transform_matrix     : Mat4,
     inv_transform_matrix : Mat4,
+    moving               : bool,
+    end_translation      : Vec3,
+    end_rotation         : Vec3,
+    end_scale_factor     : Vec3,
+    start_orientation    : Quat,
+    end_orientation      : Quat,
 }
 
 impl Default for Transform {
@@ -27,6 +48,12 @@ impl Default for Transform {
             scale_matrix         : mat4::identity(),
             transform_matrix     : mat4::identity(),
             inv_transform_matrix : mat4::identity(),
+            moving               : false,
+            end_translation      : vec3(0.0, 0.0, 0.0),
+            end_rotation         : vec3(0.0, 0.0, 0.0),
+            end_scale_factor     : vec3(1.0, 1.0, 1.0),
+            start_orientation    : quat::identity(),
+            end_orientation      : quat::identity(),
         }
     }
 }
@@ -45,15 +72,76 @@ impl Transform {
     pub fn rotate(&mut self, euler_angles: Vec3) {
         self.rotation = euler_angles;
         self.rotation_matrix = mat4::rotate(self.rotation, RotationOrder::XYZ);
+        self.start_orientation = quat::from_mat4(self.rotation_matrix);
         self.update_transform();
     }
 
+    // Pose at time 1, rotation in euler angles like rotate()
+    pub fn set_end_pose(&mut self, position: Vec3, euler_angles: Vec3, scale_factor: Vec3) {
+        self.moving = true;
+        self.end_translation = position;
+        self.end_rotation = euler_angles;
+        self.end_scale_factor = scale_factor;
+        self.end_orientation = quat::from_mat4(mat4::rotate(euler_angles, RotationOrder::XYZ));
+    }
+
+    pub fn clear_end_pose(&mut self) {
+        self.moving = false;
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    pub fn get_end_translation(&self) -> Vec3 {
+        if (self.moving) { self.end_translation } else { self.translation }
+    }
+
+    pub fn get_end_rotation(&self) -> Vec3 {
+        if (self.moving) { self.end_rotation } else { self.rotation }
+    }
+
+    pub fn get_end_scale(&self) -> Vec3 {
+        if (self.moving) { self.end_scale_factor } else { self.scale_factor }
+    }
+
+    // Static transform at a ray's time. Transforms that don't move are
+    // borrowed as they are. The interpolated euler angles are only for
+    // show, the rotation comes from the quaternions.
+    pub fn at_time(&self, time: f32) -> Cow<'_, Transform> {
+        if (!self.moving) {
+            return Cow::Borrowed(self);
+        }
+        let t = time.clamp(0.0, 1.0);
+        let mut xform = Transform::new();
+        xform.translation = vec3::mix(self.translation, self.end_translation, t);
+        xform.rotation = vec3::mix(self.rotation, self.end_rotation, t);
+        xform.scale_factor = vec3::mix(self.scale_factor, self.end_scale_factor, t);
+        xform.translation_matrix = mat4::translate(xform.translation);
+        xform.rotation_matrix = quat::to_mat4(quat::slerp(self.start_orientation, self.end_orientation, t));
+        xform.scale_matrix = mat4::scale(xform.scale_factor);
+        xform.update_transform();
+        Cow::Owned(xform)
+    }
+
     pub fn scale(&mut self, scale_factor: Vec3) {
         self.scale_factor = scale_factor;
         self.scale_matrix = mat4::scale(self.scale_factor);
         self.update_transform();
     }
 
+    pub fn get_translation(&self) -> Vec3 {
+        self.translation
+    }
+
+    pub fn get_rotation(&self) -> Vec3 {
+        self.rotation
+    }
+
+    pub fn get_scale(&self) -> Vec3 {
+        self.scale_factor
+    }
+
     fn update_transform(&mut self) {
         self.transform_matrix = self.translation_matrix * self.rotation_matrix * self.scale_matrix;
         self.inv_transform_matrix = mat4::inverse(self.transform_matrix)
@@ -69,6 +157,18 @@ impl Transform {
         vec3(v.x, v.y, v.z)
     }
 
+    // Normals go through the inverse transpose to stay perpendicular to the
+    // surface under non-uniform scale. The result is normalized.
+    pub fn local_to_world_normal(&self, normal: Vec3) -> Vec3 {
+        let m = &self.inv_transform_matrix;
+        let N = vec3(
+            vec3::dot(m.value[0].as_vec3(), normal),
+            vec3::dot(m.value[1].as_vec3(), normal),
+            vec3::dot(m.value[2].as_vec3(), normal),
+        );
+        vec3::normalize(N)
+    }
+
     pub fn world_to_local_point(&self, point: Vec3) -> Vec3 {
         let v = self.inv_transform_matrix * as_vec4(point, 1.0);
         vec3(v.x, v.y, v.z)
@@ -78,6 +178,55 @@ impl Transform {
         let v = self.inv_transform_matrix * as_vec4(vector, 0.0);
         vec3(v.x, v.y, v.z)
     }
+
+    // The direction isn't normalized, so t values along the local ray are the
+    // same as along the world ray and t_min/t_max carry over unchanged
+    pub fn world_to_local_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            pos: self.world_to_local_point(ray.pos),
+            dir: self.world_to_local_vector(ray.dir),
+            ..*ray
+        }
+    }
+
+    // Transforms all 8 corners of a local space box and bounds the result.
+    // Infinite boxes stay infinite since their corners can't be transformed.
+    // Moving transforms bound everywhere the box goes during the motion.
+    pub fn local_to_world_bounds(&self, bounds: Aabb) -> Aabb {
+        if !bounds.is_finite() {
+            return Aabb::infinite();
+        }
+        if (!self.moving) {
+            return self.corner_bounds(bounds);
+        }
+
+        // Between steps each corner moves along an arc around the pivot, which
+        // strays from the straight line covered by the steps' bounds by at
+        // most its distance from the pivot times 1 - cos(half the step's
+        // angle). Scale is interpolated per axis so that distance is largest
+        // at one of the ends.
+        let angle = 2.0 * quat::dot(self.start_orientation, self.end_orientation).abs().min(1.0).acos();
+        let step_angle = angle / (MOTION_BOUNDS_STEPS as f32);
+        let scale = vec3::max(vec3::abs(self.scale_factor), vec3::abs(self.end_scale_factor));
+        let radius = (0..8).map(|i| length(scale * bounds.corner(i))).fold(0.0, f32::max);
+        let padding = vec3::from_scalar(radius * (1.0 - (0.5 * step_angle).cos()));
+
+        let mut world_bounds = Aabb::empty();
+        for i in 0..=MOTION_BOUNDS_STEPS {
+            let step_bounds = self.at_time((i as f32) / (MOTION_BOUNDS_STEPS as f32)).corner_bounds(bounds);
+            world_bounds.grow(step_bounds.min - padding);
+            world_bounds.grow(step_bounds.max + padding);
+        }
+        world_bounds
+    }
+
+    fn corner_bounds(&self, bounds: Aabb) -> Aabb {
+        let mut world_bounds = Aabb::empty();
+        for i in 0..8 {
+            world_bounds.grow(self.local_to_world_point(bounds.corner(i)));
+        }
+        world_bounds
+    }
 }
 
 pub fn transform(position: Vec3, rotation: Vec3, scale_factor: Vec3) -> Transform
@@ -93,4 +242,30 @@ pub fn from_position(position: Vec3) -> Transform {
     let mut xform = Transform::new();
     xform.translate(position);
     xform
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aabb;
+
+    #[test]
+    fn moving_bounds_contain_motion() {
+        // A box off the pivot sweeps a wide arc when rotated 90 degrees
+        let local = aabb::aabb(vec3(4.0, -0.5, -0.5), vec3(5.0, 0.5, 0.5));
+        let mut xform = transform(vec3(1.0, 2.0, 3.0), vec3(0.0, 0.0, 0.0), vec3(1.0, 1.0, 1.0));
+        xform.set_end_pose(vec3(-2.0, 0.0, 1.0), vec3(0.0, 90f32.to_radians(), 0.0), vec3(1.0, 1.0, 1.0));
+        assert!(xform.is_moving());
+
+        let world_bounds = xform.local_to_world_bounds(local);
+        for i in 0..=10000 {
+            let time = (i as f32) / 10000.0;
+            let xform_at_time = xform.at_time(time);
+            for c in 0..8 {
+                let p = xform_at_time.local_to_world_point(local.corner(c));
+                let outside = vec3::max(world_bounds.min - p, p - world_bounds.max);
+                assert!(outside.x <= 1e-4 && outside.y <= 1e-4 && outside.z <= 1e-4, "corner {} at time {} is at {:?}, outside {:?}", c, time, p, world_bounds);
+            }
+        }
+    }
+}
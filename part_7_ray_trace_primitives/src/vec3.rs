@@ -280,6 +280,18 @@ pub fn reflect(i: Vec3, n: Vec3) -> Vec3 {
     i - (2.0 * n * dot(i, n))
 }
 
+// Same as GLSL's refract, except total internal reflection returns None
+// instead of a zero vector. eta is the ratio of the incident over the
+// transmitted index of refraction.
+pub fn refract(i: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = dot(n, i);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if (k < 0.0) {
+        return None;
+    }
+    Some((eta * i) - ((eta * cos_i + k.sqrt()) * n))
+}
+
 pub fn min(a: Vec3, b: Vec3) -> Vec3
 {
     Vec3 {
@@ -307,6 +319,24 @@ pub fn abs(v: Vec3) -> Vec3
     }
 }
 
+pub fn sqrt(v: Vec3) -> Vec3
+{
+    Vec3 {
+        x: v.x.sqrt(),
+        y: v.y.sqrt(),
+        z: v.z.sqrt(),
+    }
+}
+
+pub fn exp(v: Vec3) -> Vec3
+{
+    Vec3 {
+        x: v.x.exp(),
+        y: v.y.exp(),
+        z: v.z.exp(),
+    }
+}
+
 pub fn sign(v: Vec3) -> Vec3 {
     Vec3 {
         x: v.x.signum(),